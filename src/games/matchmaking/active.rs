@@ -1,24 +1,29 @@
-use super::PlayerInfo;
+use super::{MatchmakingError, PlayerInfo};
 use crate::rankings::{Player, Session};
 use crate::RatingType;
 
+use std::cmp::Reverse;
 use std::collections::HashMap;
 
 use skillratings::Rating;
-use worker::*;
 
 /// Gets players who have played the least games in provided session and have closest ratings
 pub(super) fn get_active_players(
     players: Vec<u16>,
     ranks: &HashMap<u16, Player<RatingType>>,
-    session: Session,
+    session: &Session,
     total_players: usize,
-) -> Result<Vec<PlayerInfo>> {
+) -> Result<Vec<PlayerInfo>, MatchmakingError> {
     let player_infos = setup_player_info(players, ranks, session)?;
     let mut active_players = find_active_players(player_infos, total_players);
-    active_players.sort_by(|a, b| (b.rating.rating() as isize).cmp(&(a.rating.rating() as isize)));
+    active_players.sort_by_key(|player| Reverse(player.rating.rating() as isize));
 
-    assert_eq!(active_players.len(), total_players);
+    if active_players.len() != total_players {
+        return Err(MatchmakingError::NotEnoughPlayers {
+            needed: total_players,
+            available: active_players.len(),
+        });
+    }
     Ok(active_players)
 }
 
@@ -28,18 +33,26 @@ pub(super) fn get_active_players(
 fn setup_player_info(
     players: Vec<u16>,
     ranks: &HashMap<u16, Player<RatingType>>,
-    session: Session,
-) -> Result<Vec<Vec<PlayerInfo>>> {
+    session: &Session,
+) -> Result<Vec<Vec<PlayerInfo>>, MatchmakingError> {
     let mut player_infos: Vec<Vec<PlayerInfo>> = vec![vec![]; (session.most_played + 1) as usize];
 
     for player in players {
-        let times_played = *session.players.get(&player).unwrap_or(&0);
+        let times_played = *session.players.get(&player).unwrap_or(&0) as usize;
         let rating = match ranks.get(&player) {
             Some(player) => player.rating,
-            None => return Err(Error::RouteNoDataError),
+            None => {
+                return Err(MatchmakingError::UnknownPlayers {
+                    players: vec![player],
+                })
+            }
         };
 
-        player_infos[times_played as usize].push(PlayerInfo { id: player, rating });
+        // Most played can be behind the counts of a session changed outside of recorded matches
+        if times_played >= player_infos.len() {
+            player_infos.resize(times_played + 1, vec![]);
+        }
+        player_infos[times_played].push(PlayerInfo { id: player, rating });
     }
 
     Ok(player_infos)
@@ -53,7 +66,7 @@ fn find_active_players(
     let mut times_played_index = 0;
     let mut total_score = 0.0;
 
-    while players.len() < total_players {
+    while players.len() < total_players && times_played_index < player_infos.len() {
         if player_infos[times_played_index].len() <= total_players - players.len() {
             for p in &player_infos[times_played_index] {
                 total_score += p.rating.rating();
@@ -91,7 +104,6 @@ fn find_active_players(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::games::matchmaking::GameInfo;
     use crate::rankings::Session;
     use crate::RatingType;
 
@@ -101,47 +113,29 @@ mod tests {
             vec![
                 PlayerInfo {
                     id: 1,
-                    rating: RatingType {
-                        rating: 2000.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((2000.0, 5.0)),
                 },
                 PlayerInfo {
                     id: 2,
-                    rating: RatingType {
-                        rating: 2100.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((2100.0, 5.0)),
                 },
                 PlayerInfo {
                     id: 3,
-                    rating: RatingType {
-                        rating: 1900.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((1900.0, 5.0)),
                 },
             ],
             vec![
                 PlayerInfo {
                     id: 4,
-                    rating: RatingType {
-                        rating: 1000.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((1000.0, 5.0)),
                 },
                 PlayerInfo {
                     id: 5,
-                    rating: RatingType {
-                        rating: 2100.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((2100.0, 5.0)),
                 },
                 PlayerInfo {
                     id: 6,
-                    rating: RatingType {
-                        rating: 2600.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((2600.0, 5.0)),
                 },
             ],
         ];
@@ -152,31 +146,19 @@ mod tests {
             vec![
                 PlayerInfo {
                     id: 1,
-                    rating: RatingType {
-                        rating: 2000.0,
-                        uncertainty: 5.0,
-                    }
+                    rating: RatingType::from((2000.0, 5.0))
                 },
                 PlayerInfo {
                     id: 2,
-                    rating: RatingType {
-                        rating: 2100.0,
-                        uncertainty: 5.0,
-                    }
+                    rating: RatingType::from((2100.0, 5.0))
                 },
                 PlayerInfo {
                     id: 3,
-                    rating: RatingType {
-                        rating: 1900.0,
-                        uncertainty: 5.0,
-                    }
+                    rating: RatingType::from((1900.0, 5.0))
                 },
                 PlayerInfo {
                     id: 5,
-                    rating: RatingType {
-                        rating: 2100.0,
-                        uncertainty: 5.0,
-                    }
+                    rating: RatingType::from((2100.0, 5.0))
                 },
             ]
         );
@@ -187,45 +169,27 @@ mod tests {
         let player_infos: Vec<Vec<PlayerInfo>> = vec![vec![
             PlayerInfo {
                 id: 1,
-                rating: RatingType {
-                    rating: 2000.0,
-                    uncertainty: 5.0,
-                },
+                rating: RatingType::from((2000.0, 5.0)),
             },
             PlayerInfo {
                 id: 2,
-                rating: RatingType {
-                    rating: 2150.0,
-                    uncertainty: 5.0,
-                },
+                rating: RatingType::from((2150.0, 5.0)),
             },
             PlayerInfo {
                 id: 3,
-                rating: RatingType {
-                    rating: 1900.0,
-                    uncertainty: 5.0,
-                },
+                rating: RatingType::from((1900.0, 5.0)),
             },
             PlayerInfo {
                 id: 4,
-                rating: RatingType {
-                    rating: 1000.0,
-                    uncertainty: 5.0,
-                },
+                rating: RatingType::from((1000.0, 5.0)),
             },
             PlayerInfo {
                 id: 5,
-                rating: RatingType {
-                    rating: 2100.0,
-                    uncertainty: 5.0,
-                },
+                rating: RatingType::from((2100.0, 5.0)),
             },
             PlayerInfo {
                 id: 6,
-                rating: RatingType {
-                    rating: 2600.0,
-                    uncertainty: 5.0,
-                },
+                rating: RatingType::from((2600.0, 5.0)),
             },
         ]];
 
@@ -235,31 +199,19 @@ mod tests {
             vec![
                 PlayerInfo {
                     id: 6,
-                    rating: RatingType {
-                        rating: 2600.0,
-                        uncertainty: 5.0,
-                    }
+                    rating: RatingType::from((2600.0, 5.0))
                 },
                 PlayerInfo {
                     id: 2,
-                    rating: RatingType {
-                        rating: 2150.0,
-                        uncertainty: 5.0,
-                    }
+                    rating: RatingType::from((2150.0, 5.0))
                 },
                 PlayerInfo {
                     id: 5,
-                    rating: RatingType {
-                        rating: 2100.0,
-                        uncertainty: 5.0,
-                    }
+                    rating: RatingType::from((2100.0, 5.0))
                 },
                 PlayerInfo {
                     id: 1,
-                    rating: RatingType {
-                        rating: 2000.0,
-                        uncertainty: 5.0,
-                    }
+                    rating: RatingType::from((2000.0, 5.0))
                 },
             ]
         );
@@ -270,12 +222,12 @@ mod tests {
         let players = vec![0, 1, 2, 3, 4, 5];
         let example = Player {
             name: "Test".to_string(),
-            rating: RatingType {
-                rating: 2000.0,
-                uncertainty: 5.0,
-            },
+            rating: RatingType::from((2000.0, 5.0)),
             wins: 0,
             losses: 0,
+            draws: 0,
+            initial_score: None,
+            archived: false,
         };
         let mut ranks = HashMap::new();
         ranks.insert(0, example.clone());
@@ -294,60 +246,42 @@ mod tests {
         session_players.insert(5, 1);
 
         let session = Session {
+            id: 0,
             players: session_players,
             most_played: 1,
-            game_info: GameInfo {
-                games: 2,
-                players_per_team: 2,
-                stability: 2.0,
-            },
+            pending: vec![],
+            next_pending_id: 0,
+            teammates: HashMap::new(),
+            opponents: HashMap::new(),
         };
 
-        let player_infos = setup_player_info(players, &ranks, session).unwrap();
+        let player_infos = setup_player_info(players, &ranks, &session).unwrap();
         let expected: Vec<Vec<PlayerInfo>> = vec![
             vec![
                 PlayerInfo {
                     id: 0,
-                    rating: RatingType {
-                        rating: 2000.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((2000.0, 5.0)),
                 },
                 PlayerInfo {
                     id: 1,
-                    rating: RatingType {
-                        rating: 2000.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((2000.0, 5.0)),
                 },
                 PlayerInfo {
                     id: 2,
-                    rating: RatingType {
-                        rating: 2000.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((2000.0, 5.0)),
                 },
                 PlayerInfo {
                     id: 3,
-                    rating: RatingType {
-                        rating: 2000.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((2000.0, 5.0)),
                 },
                 PlayerInfo {
                     id: 4,
-                    rating: RatingType {
-                        rating: 2000.0,
-                        uncertainty: 5.0,
-                    },
+                    rating: RatingType::from((2000.0, 5.0)),
                 },
             ],
             vec![PlayerInfo {
                 id: 5,
-                rating: RatingType {
-                    rating: 2000.0,
-                    uncertainty: 5.0,
-                },
+                rating: RatingType::from((2000.0, 5.0)),
             }],
         ];
         assert_eq!(player_infos, expected)
@@ -0,0 +1,486 @@
+use super::constraints::Constraints;
+use super::{Game, MatchmakingError, PlayerInfo};
+use crate::games::rating::RatingSystem;
+use crate::rankings::{Session, Settings};
+use crate::RatingType;
+
+use std::ops::Range;
+
+use rand::Rng;
+
+/// Largest single game which is balanced by trying every split of its players
+const EXHAUSTIVE_PLAYERS: usize = 12;
+const ITERATIONS: usize = 5000;
+const START_TEMPERATURE: f64 = 0.1;
+const END_TEMPERATURE: f64 = 0.0001;
+/// Added for every broken constraint, high enough that no balance or repeat is worth breaking one
+const VIOLATION_COST: f64 = 1000.0;
+
+/// Scores a game, lower is better
+pub(super) struct GameCost<'a> {
+    pub(super) team_sizes: (usize, usize),
+    pub(super) rating_system: RatingSystem,
+    pub(super) settings: &'a Settings,
+    pub(super) session: &'a Session,
+    pub(super) repeat_penalty: f64,
+    pub(super) constraints: &'a Constraints,
+}
+
+impl GameCost<'_> {
+    fn per_game(&self) -> usize {
+        self.team_sizes.0 + self.team_sizes.1
+    }
+
+    /// Index of the team the slot is on, counting both teams of every game
+    fn team_index(&self, slot: usize) -> usize {
+        let game = slot / self.per_game();
+        game * 2 + usize::from(slot % self.per_game() >= self.team_sizes.0)
+    }
+
+    /// Slots of the team the slot is on
+    fn team(&self, slot: usize) -> Range<usize> {
+        let start = slot / self.per_game() * self.per_game();
+        if slot - start < self.team_sizes.0 {
+            start..start + self.team_sizes.0
+        } else {
+            start + self.team_sizes.0..start + self.per_game()
+        }
+    }
+
+    /// How far the game is from an even chance of either team winning, plus the repeat penalty for
+    /// every time a pair of teammates or opponents already played together this session and the
+    /// violation cost for every broken constraint
+    fn cost(&self, game: &[PlayerInfo]) -> f64 {
+        let ratings =
+            |team: &[PlayerInfo]| -> Vec<RatingType> { team.iter().map(|p| p.rating).collect() };
+        let (team1, team2) = game.split_at(self.team_sizes.0);
+        let (win, _) =
+            self.rating_system
+                .expected_score(self.settings, &ratings(team1), &ratings(team2));
+
+        (win - 0.5).abs()
+            + self.repeat_penalty * self.repeats(team1, team2) as f64
+            + VIOLATION_COST * self.violations(team1, team2) as f64
+    }
+
+    fn violations(&self, team1: &[PlayerInfo], team2: &[PlayerInfo]) -> usize {
+        let ids = |team: &[PlayerInfo]| -> Vec<u16> { team.iter().map(|p| p.id).collect() };
+        self.constraints.violations(&ids(team1), &ids(team2))
+    }
+
+    fn repeats(&self, team1: &[PlayerInfo], team2: &[PlayerInfo]) -> u16 {
+        let teammates = |team: &[PlayerInfo]| -> u16 {
+            team.iter()
+                .enumerate()
+                .flat_map(|(i, player)| {
+                    team[i + 1..]
+                        .iter()
+                        .map(move |other| self.session.teammate_count(player.id, other.id))
+                })
+                .sum()
+        };
+        let opponents: u16 = team1
+            .iter()
+            .flat_map(|player| {
+                team2
+                    .iter()
+                    .map(move |other| self.session.opponent_count(player.id, other.id))
+            })
+            .sum();
+
+        teammates(team1) + teammates(team2) + opponents
+    }
+}
+
+/// Splits the active players into games with the lowest total [`GameCost`], which without a repeat
+/// penalty is where each team's predicted chance of winning is as close to even as possible.
+///
+/// Teams can have different sizes, in which case the smaller team needs stronger players for an
+/// even chance with rating systems which add up a team's ratings.
+///
+/// A single game with few enough players is balanced by trying every split. Otherwise players are
+/// snake drafted into teams by rating and then swapped between teams with simulated annealing,
+/// which only depends on the provided rng so the same seed gives the same games. Players who have
+/// to be kept together are swapped as a group.
+///
+/// Fails if the best games found still break a constraint.
+pub(super) fn balance_matches(
+    players: Vec<PlayerInfo>,
+    cost: &GameCost,
+    rng: &mut impl Rng,
+) -> Result<Vec<Game>, MatchmakingError> {
+    let (team1_size, _) = cost.team_sizes;
+    let per_game = cost.per_game();
+
+    let slots = if players.len() == per_game && per_game <= EXHAUSTIVE_PLAYERS {
+        exhaustive(players, cost)
+    } else {
+        anneal(snake_draft(players, cost.team_sizes), cost, rng)
+    };
+
+    let games: Vec<Game> = slots
+        .chunks(per_game)
+        .enumerate()
+        .map(|(id, game)| Game {
+            id: id as u16,
+            team1: game[..team1_size].iter().map(|p| p.id).collect(),
+            team2: game[team1_size..].iter().map(|p| p.id).collect(),
+            seed: None,
+        })
+        .collect();
+
+    if games
+        .iter()
+        .any(|game| cost.constraints.violations(&game.team1, &game.team2) > 0)
+    {
+        return Err(MatchmakingError::Unsatisfiable);
+    }
+    Ok(games)
+}
+
+fn exhaustive(players: Vec<PlayerInfo>, cost: &GameCost) -> Vec<PlayerInfo> {
+    let mut best = players.clone();
+    let mut best_cost = f64::INFINITY;
+
+    // With even teams the first player is always on team 1 so each split is only tried once
+    let even = cost.team_sizes.0 == cost.team_sizes.1;
+    for mask in 0..1u32 << players.len() {
+        if (even && mask & 1 == 0) || mask.count_ones() as usize != cost.team_sizes.0 {
+            continue;
+        }
+
+        let on_team1 = |i: usize| mask & (1 << i) != 0;
+        let team = |team1: bool| {
+            players
+                .iter()
+                .enumerate()
+                .filter(move |(i, _)| on_team1(*i) == team1)
+                .map(|(_, p)| *p)
+        };
+        let game: Vec<PlayerInfo> = team(true).chain(team(false)).collect();
+
+        let game_cost = cost.cost(&game);
+        if game_cost < best_cost {
+            best_cost = game_cost;
+            best = game;
+        }
+    }
+
+    best
+}
+
+/// Deals players sorted by rating into teams, reversing direction every round so the strongest
+/// teams pick last. Teams which are full are skipped.
+fn snake_draft(players: Vec<PlayerInfo>, team_sizes: (usize, usize)) -> Vec<PlayerInfo> {
+    let per_game = team_sizes.0 + team_sizes.1;
+    let teams = players.len() / per_game * 2;
+    // Size of the team and where it starts in its game
+    let team_slots = |team: usize| {
+        if team % 2 == 1 {
+            (team_sizes.1, team_sizes.0)
+        } else {
+            (team_sizes.0, 0)
+        }
+    };
+
+    let mut slots = players.clone();
+    let mut filled = vec![0; teams];
+    let mut players = players.into_iter().take(teams / 2 * per_game).peekable();
+    let mut round: usize = 0;
+    while players.peek().is_some() {
+        let order: Vec<usize> = if round % 2 == 1 {
+            (0..teams).rev().collect()
+        } else {
+            (0..teams).collect()
+        };
+
+        for team in order {
+            let (size, offset) = team_slots(team);
+            if filled[team] == size {
+                continue;
+            }
+            if let Some(player) = players.next() {
+                slots[team / 2 * per_game + offset + filled[team]] = player;
+                filled[team] += 1;
+            }
+        }
+        round += 1;
+    }
+
+    slots
+}
+
+fn anneal(mut slots: Vec<PlayerInfo>, cost: &GameCost, rng: &mut impl Rng) -> Vec<PlayerInfo> {
+    let per_game = cost.per_game();
+    let game_cost = |slots: &[PlayerInfo], game: usize| {
+        cost.cost(&slots[game * per_game..(game + 1) * per_game])
+    };
+
+    let mut costs: Vec<f64> = (0..slots.len() / per_game)
+        .map(|game| game_cost(&slots, game))
+        .collect();
+    let mut total: f64 = costs.iter().sum();
+    let mut best = slots.clone();
+    let mut best_total = total;
+
+    let cooling = (END_TEMPERATURE / START_TEMPERATURE).powf(1.0 / ITERATIONS as f64);
+    let mut temperature = START_TEMPERATURE;
+
+    for _ in 0..ITERATIONS {
+        temperature *= cooling;
+
+        let i = rng.gen_range(0..slots.len());
+        let j = rng.gen_range(0..slots.len());
+        if cost.team_index(i) == cost.team_index(j) {
+            continue;
+        }
+        let (units_i, units_j) = match swap_units(&slots, i, j, cost, rng) {
+            Some(units) => units,
+            None => continue,
+        };
+
+        let swap = |slots: &mut [PlayerInfo]| {
+            units_i
+                .iter()
+                .zip(&units_j)
+                .for_each(|(a, b)| slots.swap(*a, *b))
+        };
+        swap(&mut slots);
+        let (game_i, game_j) = (i / per_game, j / per_game);
+        let new_i = game_cost(&slots, game_i);
+        let new_j = if game_i == game_j {
+            new_i
+        } else {
+            game_cost(&slots, game_j)
+        };
+        let delta = if game_i == game_j {
+            new_i - costs[game_i]
+        } else {
+            new_i + new_j - costs[game_i] - costs[game_j]
+        };
+
+        if delta <= 0.0 || rng.gen::<f64>() < (-delta / temperature).exp() {
+            costs[game_i] = new_i;
+            costs[game_j] = new_j;
+            total += delta;
+
+            if total < best_total {
+                best_total = total;
+                best = slots.clone();
+            }
+        } else {
+            swap(&mut slots);
+        }
+    }
+
+    best
+}
+
+/// Slots to swap between the teams of slots `i` and `j`. A player who has to be kept together is
+/// moved with the rest of their group on their team, and the other side is filled up with players
+/// from its team who aren't in a group. None if there aren't enough of them.
+fn swap_units(
+    slots: &[PlayerInfo],
+    i: usize,
+    j: usize,
+    cost: &GameCost,
+    rng: &mut impl Rng,
+) -> Option<(Vec<usize>, Vec<usize>)> {
+    let unit = |slot: usize| -> Vec<usize> {
+        match cost.constraints.group(slots[slot].id) {
+            Some(group) => cost
+                .team(slot)
+                .filter(|other| group.contains(&slots[*other].id))
+                .collect(),
+            None => vec![slot],
+        }
+    };
+
+    let mut units_i = unit(i);
+    let mut units_j = unit(j);
+    if units_i.len() == units_j.len() {
+        return Some((units_i, units_j));
+    }
+
+    let larger = units_i.len().max(units_j.len());
+    let (smaller, slot) = if units_i.len() < units_j.len() {
+        (&mut units_i, i)
+    } else {
+        (&mut units_j, j)
+    };
+    let needed = larger - smaller.len();
+    let mut fill: Vec<usize> = cost
+        .team(slot)
+        .filter(|other| {
+            !smaller.contains(other) && cost.constraints.group(slots[*other].id).is_none()
+        })
+        .collect();
+    if fill.len() < needed {
+        return None;
+    }
+
+    let start = rng.gen_range(0..fill.len());
+    fill.rotate_left(start);
+    smaller.extend(&fill[..needed]);
+    Some((units_i, units_j))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::games::fixtures::two_team_match;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    fn player_infos(ratings: &[f64]) -> Vec<PlayerInfo> {
+        ratings
+            .iter()
+            .enumerate()
+            .map(|(id, rating)| PlayerInfo {
+                id: id as u16,
+                rating: RatingType::from((*rating, 8.0)),
+            })
+            .collect()
+    }
+
+    fn game_cost<'a>(
+        team_sizes: (usize, usize),
+        settings: &'a Settings,
+        session: &'a Session,
+        constraints: &'a Constraints,
+        repeat_penalty: f64,
+    ) -> GameCost<'a> {
+        GameCost {
+            team_sizes,
+            rating_system: RatingSystem::TrueSkill,
+            settings,
+            session,
+            repeat_penalty,
+            constraints,
+        }
+    }
+
+    #[test]
+    fn test_snake_draft() {
+        let players = player_infos(&[40.0, 35.0, 30.0, 25.0, 20.0, 15.0, 10.0, 5.0]);
+        let ids: Vec<u16> = snake_draft(players, (2, 2)).iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![0, 7, 1, 6, 2, 5, 3, 4]);
+
+        let players = player_infos(&[40.0, 35.0, 30.0, 25.0, 20.0, 15.0, 10.0, 5.0, 0.0, 0.0]);
+        let ids: Vec<u16> = snake_draft(players, (2, 3)).iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![0, 7, 1, 6, 8, 2, 5, 3, 4, 9]);
+    }
+
+    #[test]
+    fn test_exhaustive() {
+        let settings = Settings::new(RatingSystem::TrueSkill);
+        let session = Session::default();
+        let constraints = Constraints::default();
+        let players = player_infos(&[40.0, 30.0, 25.0, 20.0, 15.0, 10.0]);
+
+        let games = balance_matches(
+            players,
+            &game_cost((3, 3), &settings, &session, &constraints, 0.0),
+            &mut ChaCha8Rng::seed_from_u64(0),
+        )
+        .unwrap();
+        let mut team1 = games[0].team1.clone();
+        team1.sort();
+        assert_eq!(team1, vec![0, 3, 5]);
+    }
+
+    #[test]
+    fn test_exhaustive_uneven() {
+        let settings = Settings::new(RatingSystem::TrueSkill);
+        let session = Session::default();
+        let constraints = Constraints::default();
+        let players = player_infos(&[40.0, 30.0, 25.0, 20.0, 15.0]);
+
+        // The two player team needs the strongest players to have an even chance
+        let games = balance_matches(
+            players,
+            &game_cost((2, 3), &settings, &session, &constraints, 0.0),
+            &mut ChaCha8Rng::seed_from_u64(0),
+        )
+        .unwrap();
+        let mut team1 = games[0].team1.clone();
+        team1.sort();
+        assert_eq!(team1, vec![0, 2]);
+        assert_eq!(games[0].team2.len(), 3);
+    }
+
+    #[test]
+    fn test_repeat_penalty() {
+        let settings = Settings::new(RatingSystem::TrueSkill);
+        let mut session = Session::default();
+        let constraints = Constraints::default();
+        let players = player_infos(&[30.0, 30.0, 30.0, 30.0]);
+
+        // 0 and 1 already played together so they should be split up
+        let played = two_team_match(0, vec![0, 1], vec![2, 3]);
+        session.add_pairs(&played);
+
+        let games = balance_matches(
+            players,
+            &game_cost((2, 2), &settings, &session, &constraints, 0.1),
+            &mut ChaCha8Rng::seed_from_u64(0),
+        )
+        .unwrap();
+        assert!(!games[0].team1.contains(&1));
+    }
+
+    #[test]
+    fn test_anneal() {
+        let settings = Settings::new(RatingSystem::TrueSkill);
+        let session = Session::default();
+        let constraints = Constraints::default();
+        let cost = game_cost((2, 2), &settings, &session, &constraints, 0.0);
+        let ratings = [
+            42.0, 38.0, 35.0, 33.0, 30.0, 29.0, 27.0, 25.0, 24.0, 22.0, 20.0, 18.0, 15.0, 12.0,
+            10.0, 6.0,
+        ];
+
+        let drafted = snake_draft(player_infos(&ratings), (2, 2));
+        let drafted_cost: f64 = drafted.chunks(4).map(|game| cost.cost(game)).sum();
+
+        let generate = |seed| {
+            balance_matches(
+                player_infos(&ratings),
+                &cost,
+                &mut ChaCha8Rng::seed_from_u64(seed),
+            )
+            .unwrap()
+        };
+        let games = generate(7);
+        assert_eq!(games.len(), 4);
+
+        let mut ids: Vec<u16> = games
+            .iter()
+            .flat_map(|game| game.team1.iter().chain(&game.team2).copied())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, (0..16).collect::<Vec<u16>>());
+
+        let player = |id: &u16| PlayerInfo {
+            id: *id,
+            rating: RatingType::from((ratings[*id as usize], 8.0)),
+        };
+        let total: f64 = games
+            .iter()
+            .map(|game| {
+                let slots: Vec<PlayerInfo> =
+                    game.team1.iter().chain(&game.team2).map(player).collect();
+                cost.cost(&slots)
+            })
+            .sum();
+        assert!(total <= drafted_cost);
+
+        // Same seed gives the same games
+        let again = generate(7);
+        for (a, b) in games.iter().zip(&again) {
+            assert_eq!(a.team1, b.team1);
+            assert_eq!(a.team2, b.team2);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use super::{GameInfo, MatchmakingError, PlayerInfo};
+use crate::rankings::{Player, Session};
+use crate::RatingType;
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use skillratings::Rating;
+
+/// Keep together and keep apart constraints for the players taking part in matchmaking
+#[derive(Debug, Default)]
+pub(super) struct Constraints {
+    groups: Vec<Vec<u16>>,
+    apart: Vec<[u16; 2]>,
+}
+
+impl Constraints {
+    /// Players who aren't taking part are left out and groups sharing a player are merged, since
+    /// they all have to be on the same team
+    pub(super) fn new(game_info: &GameInfo, players: &[u16]) -> Result<Self, MatchmakingError> {
+        let mut groups: Vec<Vec<u16>> = vec![];
+        for group in &game_info.keep_together {
+            let mut merged: Vec<u16> = group
+                .iter()
+                .filter(|player| players.contains(player))
+                .copied()
+                .collect();
+
+            groups.retain(|other| {
+                if other.iter().any(|player| merged.contains(player)) {
+                    merged.extend(other);
+                    false
+                } else {
+                    true
+                }
+            });
+            merged.sort();
+            merged.dedup();
+            groups.push(merged);
+        }
+        groups.retain(|group| group.len() > 1);
+
+        let apart: Vec<[u16; 2]> = game_info
+            .keep_apart
+            .iter()
+            .filter(|pair| pair[0] != pair[1] && pair.iter().all(|p| players.contains(p)))
+            .copied()
+            .collect();
+
+        let (team1, team2) = game_info.team_sizes();
+        if let Some(group) = groups.iter().find(|group| group.len() > team1.max(team2)) {
+            return Err(MatchmakingError::GroupTooLarge {
+                players: group.clone(),
+            });
+        }
+        if let Some(pair) = apart.iter().find(|pair| {
+            groups
+                .iter()
+                .any(|g| g.contains(&pair[0]) && g.contains(&pair[1]))
+        }) {
+            return Err(MatchmakingError::ConflictingConstraints {
+                players: pair.to_vec(),
+            });
+        }
+
+        Ok(Constraints { groups, apart })
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.groups.is_empty() && self.apart.is_empty()
+    }
+
+    pub(super) fn group(&self, player: u16) -> Option<&[u16]> {
+        self.groups
+            .iter()
+            .find(|group| group.contains(&player))
+            .map(|group| group.as_slice())
+    }
+
+    /// Number of groups split up and pairs put on the same team in a game. A group is split if any
+    /// of its players are in the game without all of them being on one team.
+    pub(super) fn violations(&self, team1: &[u16], team2: &[u16]) -> usize {
+        let split = self
+            .groups
+            .iter()
+            .filter(|group| {
+                let in_team = |team: &[u16]| group.iter().filter(|p| team.contains(p)).count();
+                let (one, two) = (in_team(team1), in_team(team2));
+                one + two > 0 && one != group.len() && two != group.len()
+            })
+            .count();
+        let together = self
+            .apart
+            .iter()
+            .filter(|pair| {
+                [team1, team2]
+                    .iter()
+                    .any(|team| team.contains(&pair[0]) && team.contains(&pair[1]))
+            })
+            .count();
+
+        split + together
+    }
+
+    /// Makes sure every group is either fully playing or fully sitting out. Missing players of a
+    /// group replace the players outside of any group who have played the most this session.
+    pub(super) fn keep_groups(
+        &self,
+        mut active_players: Vec<PlayerInfo>,
+        ranks: &HashMap<u16, Player<RatingType>>,
+        session: &Session,
+    ) -> Result<Vec<PlayerInfo>, MatchmakingError> {
+        for group in &self.groups {
+            let missing: Vec<u16> = group
+                .iter()
+                .filter(|player| !active_players.iter().any(|p| p.id == **player))
+                .copied()
+                .collect();
+            if missing.len() == group.len() || missing.is_empty() {
+                continue;
+            }
+
+            let mut replaceable: Vec<usize> = (0..active_players.len())
+                .filter(|i| self.group(active_players[*i].id).is_none())
+                .collect();
+            if replaceable.len() < missing.len() {
+                return Err(MatchmakingError::Unsatisfiable);
+            }
+            replaceable.sort_by_key(|i| {
+                let played = session.players.get(&active_players[*i].id);
+                (Reverse(played.copied().unwrap_or(0)), Reverse(*i))
+            });
+
+            let mut replaced: Vec<usize> = replaceable[..missing.len()].to_vec();
+            replaced.sort_by_key(|i| Reverse(*i));
+            for i in replaced {
+                active_players.remove(i);
+            }
+            for player in missing {
+                let rating = ranks
+                    .get(&player)
+                    .ok_or(MatchmakingError::Unsatisfiable)?
+                    .rating;
+                active_players.push(PlayerInfo { id: player, rating });
+            }
+        }
+
+        active_players.sort_by_key(|player| Reverse(player.rating.rating() as isize));
+        Ok(active_players)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game_info(keep_together: Vec<Vec<u16>>, keep_apart: Vec<[u16; 2]>) -> GameInfo {
+        GameInfo {
+            games: 1,
+            players_per_team: 3,
+            stability: 10.0,
+            team2_players: None,
+            strategy: Default::default(),
+            seed: None,
+            repeat_penalty: 0.0,
+            keep_together,
+            keep_apart,
+        }
+    }
+
+    #[test]
+    fn test_new() {
+        let players: Vec<u16> = (0..8).collect();
+
+        let constraints = Constraints::new(
+            &game_info(vec![vec![0, 1], vec![1, 2], vec![3, 9]], vec![]),
+            &players,
+        )
+        .unwrap();
+        assert_eq!(constraints.groups, vec![vec![0, 1, 2]]);
+        assert_eq!(constraints.group(2), Some(&[0, 1, 2][..]));
+        assert_eq!(constraints.group(3), None);
+
+        assert_eq!(
+            Constraints::new(
+                &game_info(vec![vec![0, 1], vec![2, 3, 1]], vec![]),
+                &players
+            )
+            .unwrap_err(),
+            MatchmakingError::GroupTooLarge {
+                players: vec![0, 1, 2, 3]
+            }
+        );
+        assert_eq!(
+            Constraints::new(
+                &game_info(vec![vec![0, 1], vec![1, 2]], vec![[2, 0]]),
+                &players
+            )
+            .unwrap_err(),
+            MatchmakingError::ConflictingConstraints {
+                players: vec![2, 0]
+            }
+        );
+        assert!(Constraints::new(&game_info(vec![], vec![[2, 9]]), &players)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_violations() {
+        let players: Vec<u16> = (0..8).collect();
+        let constraints =
+            Constraints::new(&game_info(vec![vec![0, 1]], vec![[2, 3]]), &players).unwrap();
+
+        assert_eq!(constraints.violations(&[0, 1, 2], &[3, 4, 5]), 0);
+        assert_eq!(constraints.violations(&[0, 2, 3], &[1, 4, 5]), 2);
+        assert_eq!(constraints.violations(&[0, 2, 4], &[3, 5, 6]), 1);
+        assert_eq!(constraints.violations(&[4, 5, 6], &[2, 7, 3]), 1);
+    }
+
+    #[test]
+    fn test_keep_groups() {
+        let players: Vec<u16> = (0..6).collect();
+        let constraints = Constraints::new(&game_info(vec![vec![0, 5]], vec![]), &players).unwrap();
+        let ranks: HashMap<u16, Player<RatingType>> = players
+            .iter()
+            .map(|id| {
+                (
+                    *id,
+                    Player {
+                        name: id.to_string(),
+                        rating: RatingType::from((30.0 - *id as f64, 8.0)),
+                        wins: 0,
+                        losses: 0,
+                        draws: 0,
+                        initial_score: None,
+                        archived: false,
+                    },
+                )
+            })
+            .collect();
+        let info = |id: u16| PlayerInfo {
+            id,
+            rating: ranks[&id].rating,
+        };
+        let session = Session {
+            players: HashMap::from([(0, 0), (1, 1), (2, 2), (3, 0), (4, 0), (5, 0)]),
+            ..Default::default()
+        };
+
+        // 2 has played the most so sits out for 5
+        let active = vec![info(0), info(1), info(2), info(3)];
+        let ids: Vec<u16> = constraints
+            .keep_groups(active, &ranks, &session)
+            .unwrap()
+            .iter()
+            .map(|p| p.id)
+            .collect();
+        assert_eq!(ids, vec![0, 1, 3, 5]);
+
+        let active = vec![info(1), info(2), info(3), info(4)];
+        let ids: Vec<u16> = constraints
+            .keep_groups(active, &ranks, &session)
+            .unwrap()
+            .iter()
+            .map(|p| p.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+    }
+}
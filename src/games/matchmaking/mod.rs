@@ -1,13 +1,20 @@
 mod active;
+mod balanced;
+mod constraints;
 
-use crate::rankings::{Match, Player, Session};
+use crate::games::rating::RatingSystem;
+use crate::rankings::{Player, Session, Settings};
 use crate::RatingType;
 use active::get_active_players;
+use balanced::{balance_matches, GameCost};
+use constraints::Constraints;
 
 use std::cmp;
 use std::collections::{HashMap, VecDeque};
 
 use getrandom::getrandom;
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
 use serde::{Deserialize, Serialize};
 use skillratings::Rating;
 use worker::*;
@@ -18,13 +25,130 @@ struct PlayerInfo {
     rating: RatingType,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+/// Generated game between two teams which has not been played yet
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Game {
+    pub(crate) id: u16,
+    pub(crate) team1: Vec<u16>,
+    pub(crate) team2: Vec<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<u64>, // Seed the game was generated with, None for older games
+}
+
+/// How players are split into teams once the active players are chosen
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Teams are built one player at a time with random picks weighted by rating
+    #[default]
+    Greedy,
+    /// Teams are searched for the most even predicted chance of winning
+    Balanced,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GameInfo {
     pub games: usize,
     pub players_per_team: usize,
     pub stability: f64,
+    /// Players on the second team of each game when it differs from players per team, such as 3
+    /// for 2v3 games
+    #[serde(default)]
+    pub team2_players: Option<usize>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Seed for the matchmaking rng, a random seed is used if not set
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// How much to avoid pairing players who were already teammates or opponents this session.
+    /// Every repeat is added to how far the game's predicted win chance is from even, so 0.05
+    /// treats each repeat like a 5% imbalance.
+    #[serde(default)]
+    pub repeat_penalty: f64,
+    /// Groups of players who have to be on the same team
+    #[serde(default)]
+    pub keep_together: Vec<Vec<u16>>,
+    /// Pairs of players who can't be on the same team
+    #[serde(default)]
+    pub keep_apart: Vec<[u16; 2]>,
+}
+
+/// Reasons matches can't be generated
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum MatchmakingError {
+    NoGames,
+    /// A team would have no players
+    EmptyTeam,
+    /// Fewer players are taking part than are needed for one game
+    NotEnoughPlayers {
+        needed: usize,
+        available: usize,
+    },
+    UnknownPlayers {
+        players: Vec<u16>,
+    },
+    DuplicatePlayers {
+        players: Vec<u16>,
+    },
+    /// Stability has to be a number of at least 0
+    InvalidStability,
+    /// Repeat penalty has to be a number from 0 to 0.5
+    InvalidRepeatPenalty,
+    /// More players have to be kept together than fit on one team
+    GroupTooLarge {
+        players: Vec<u16>,
+    },
+    /// Players have to be kept together and apart
+    ConflictingConstraints {
+        players: Vec<u16>,
+    },
+    /// No games could be found which keep every constraint
+    Unsatisfiable,
+    /// Matches can only be generated during a session
+    NoSession,
+}
+
+impl GameInfo {
+    /// Players on the first and second team of each game
+    pub fn team_sizes(&self) -> (usize, usize) {
+        (
+            self.players_per_team,
+            self.team2_players.unwrap_or(self.players_per_team),
+        )
+    }
+
+    /// Strategy games are generated with for the players. Greedy picks only compare the rating
+    /// totals of teams of the same size and can't keep players together or apart or weigh repeats,
+    /// so the balanced search is used for those whichever strategy was chosen.
+    pub fn strategy_used(&self, players: &[u16]) -> Strategy {
+        let constrained = Constraints::new(self, players).is_ok_and(|c| !c.is_empty());
+        let (team1, team2) = self.team_sizes();
+
+        if constrained || team1 != team2 || self.repeat_penalty > 0.0 {
+            Strategy::Balanced
+        } else {
+            self.strategy
+        }
+    }
+
+    /// Provided seed or a new random one. Random seeds are kept within 53 bits so they can be sent
+    /// back unchanged from javascript.
+    pub fn seed(&self) -> Result<u64> {
+        match self.seed {
+            Some(seed) => Ok(seed),
+            None => {
+                let mut val: [u8; 8] = [0; 8];
+                getrandom(&mut val).map_err(|err| err.to_string())?;
+                Ok(u64::from_le_bytes(val) & ((1 << 53) - 1))
+            }
+        }
+    }
 }
 
+/// Rng used for matchmaking, the same seed and players always generate the same matches
+pub type MatchRng = ChaCha8Rng;
+
 /// First active players are selected, this will be players in the provided session who have played
 /// the least number of games and have closest ratings.
 ///
@@ -39,27 +163,64 @@ pub struct GameInfo {
 /// difference in rating between team 1 and team 2.
 ///
 /// Once a game is full it is added and this process repeats until all games are filled.
+///
+/// With the balanced strategy the active players are instead split by
+/// [`balance_matches`](balanced::balance_matches) using the rating system's predictions. The
+/// balanced search is also used in the cases listed by [`GameInfo::strategy_used`], with groups
+/// kept together always playing or sitting out as a whole.
+///
+/// Every random choice is made with the provided rng.
+///
+/// Fails if the games can't be filled from the provided players, which are checked before any
+/// matches are generated.
 pub fn generate_matches(
     players: Vec<u16>,
     ranks: &HashMap<u16, Player<RatingType>>,
     session: Session,
     game_info: GameInfo,
-) -> Result<Vec<Match>> {
-    let mut matches: Vec<Match> = vec![];
+    rating_system: RatingSystem,
+    settings: &Settings,
+    rng: &mut impl Rng,
+) -> std::result::Result<Vec<Game>, MatchmakingError> {
+    let mut matches: Vec<Game> = vec![];
 
-    let per_game = game_info.players_per_team * 2;
+    validate(&players, ranks, &game_info)?;
+    let constraints = Constraints::new(&game_info, &players)?;
+
+    let team_sizes = game_info.team_sizes();
+    let per_game = team_sizes.0 + team_sizes.1;
     let games = cmp::min(players.len() / per_game, game_info.games);
     let total_players = games * per_game;
 
-    let mut active_players =
-        VecDeque::from(get_active_players(players, ranks, session, total_players)?);
+    let strategy = game_info.strategy_used(&players);
+    let active_players = get_active_players(players, ranks, &session, total_players)?;
+
+    if strategy == Strategy::Balanced {
+        let active_players = constraints.keep_groups(active_players, ranks, &session)?;
+        let cost = GameCost {
+            team_sizes,
+            rating_system,
+            settings,
+            session: &session,
+            repeat_penalty: game_info.repeat_penalty,
+            constraints: &constraints,
+        };
+        return balance_matches(active_players, &cost, rng);
+    }
+
+    let mut active_players = VecDeque::from(active_players);
+    // Can't happen once the active players are found but a game is never left half filled
+    let not_enough = |found: usize| MatchmakingError::NotEnoughPlayers {
+        needed: per_game,
+        available: found,
+    };
 
     for _i in 0..games {
         let mut team1: Vec<u16> = vec![];
         let mut team2: Vec<u16> = vec![];
 
         // Get top player
-        let top_player = active_players.pop_front().unwrap();
+        let top_player = active_players.pop_front().ok_or(not_enough(0))?;
         team1.push(top_player.id);
 
         let active_player_scores: Vec<f64> = active_players
@@ -71,9 +232,11 @@ pub fn generate_matches(
         let top_player2 = find_player(
             top_player.rating.rating(),
             active_player_scores,
-            game_info.stability,
+            &game_info,
             &mut active_players,
-        );
+            rng,
+        )
+        .ok_or(not_enough(1))?;
         team2.push(top_player2.id);
 
         let mut overall_diff = top_player.rating.rating() - top_player2.rating.rating();
@@ -87,12 +250,15 @@ pub fn generate_matches(
                 .collect();
 
             // Find next player to team 1 closest to selected players average scores
+            let picked = team1.len() + team2.len();
             let next_player = find_player(
                 score_avg,
                 active_player_scores,
-                game_info.stability,
+                &game_info,
                 &mut active_players,
-            );
+                rng,
+            )
+            .ok_or(not_enough(picked))?;
 
             // Find player for team 2 where diff(next_player, next_player2) is closest to diff
             // between teams
@@ -103,9 +269,11 @@ pub fn generate_matches(
             let next_player2 = find_player(
                 overall_diff.abs(),
                 diffs,
-                game_info.stability,
+                &game_info,
                 &mut active_players,
-            );
+                rng,
+            )
+            .ok_or(not_enough(picked + 1))?;
 
             if (next_player.rating.rating() - next_player2.rating.rating()).is_sign_positive()
                 == overall_diff.is_sign_positive()
@@ -123,32 +291,87 @@ pub fn generate_matches(
             overall_score += next_player2.rating.rating();
         }
 
-        matches.push(Match {
+        matches.push(Game {
             id: matches.len() as u16,
             team1,
             team2,
+            seed: None,
         });
     }
 
     Ok(matches)
 }
 
+/// Checks the game info and players can fill at least one game
+fn validate(
+    players: &[u16],
+    ranks: &HashMap<u16, Player<RatingType>>,
+    game_info: &GameInfo,
+) -> std::result::Result<(), MatchmakingError> {
+    let (team1, team2) = game_info.team_sizes();
+    if game_info.games == 0 {
+        return Err(MatchmakingError::NoGames);
+    }
+    if team1 == 0 || team2 == 0 {
+        return Err(MatchmakingError::EmptyTeam);
+    }
+    if !game_info.stability.is_finite() || game_info.stability < 0.0 {
+        return Err(MatchmakingError::InvalidStability);
+    }
+    if !(0.0..=0.5).contains(&game_info.repeat_penalty) {
+        return Err(MatchmakingError::InvalidRepeatPenalty);
+    }
+
+    let unknown: Vec<u16> = players
+        .iter()
+        .filter(|player| !ranks.contains_key(player))
+        .copied()
+        .collect();
+    if !unknown.is_empty() {
+        return Err(MatchmakingError::UnknownPlayers { players: unknown });
+    }
+
+    let mut duplicates: Vec<u16> = players
+        .iter()
+        .enumerate()
+        .filter(|(i, player)| players[..*i].contains(player))
+        .map(|(_, player)| *player)
+        .collect();
+    duplicates.sort_unstable();
+    duplicates.dedup();
+    if !duplicates.is_empty() {
+        return Err(MatchmakingError::DuplicatePlayers {
+            players: duplicates,
+        });
+    }
+
+    if players.len() < team1 + team2 {
+        return Err(MatchmakingError::NotEnoughPlayers {
+            needed: team1 + team2,
+            available: players.len(),
+        });
+    }
+
+    Ok(())
+}
+
 fn find_player(
     player_score: f64,
     other_scores: Vec<f64>,
-    stability: f64,
+    game_info: &GameInfo,
     active_players: &mut VecDeque<PlayerInfo>,
-) -> PlayerInfo {
-    let probs = get_prob(player_score, other_scores, stability);
+    rng: &mut impl Rng,
+) -> Option<PlayerInfo> {
+    let probs = get_prob(player_score, other_scores, game_info.stability);
 
-    let rng_val = rng();
+    let rng_val: f64 = rng.gen();
     for (i, prob) in probs.iter().enumerate() {
         if rng_val < *prob {
-            return active_players.remove(i).unwrap();
+            return active_players.remove(i);
         }
     }
 
-    active_players.pop_back().unwrap()
+    active_players.pop_back()
 }
 
 fn get_prob(player: f64, others: Vec<f64>, stability: f64) -> Vec<f64> {
@@ -162,13 +385,24 @@ fn get_prob(player: f64, others: Vec<f64>, stability: f64) -> Vec<f64> {
         total += p;
     });
 
+    // Every player is equally close when all ratings are the same
     let mut prob_total = 0.0;
     probs.iter_mut().for_each(|prob| {
-        *prob = 1.0 - (*prob / total);
+        *prob = if total > 0.0 {
+            1.0 - (*prob / total)
+        } else {
+            1.0
+        };
         *prob = prob.powf(stability);
         prob_total += *prob;
     });
 
+    // Only a single player left, or the others are all too far to be chosen, so pick evenly
+    if prob_total <= 0.0 || !prob_total.is_finite() {
+        probs.iter_mut().for_each(|prob| *prob = 1.0);
+        prob_total = probs.len() as f64;
+    }
+
     let mut last = 0.0;
     probs.iter_mut().for_each(|prob| {
         *prob = *prob / prob_total + last;
@@ -178,15 +412,451 @@ fn get_prob(player: f64, others: Vec<f64>, stability: f64) -> Vec<f64> {
     probs
 }
 
-fn rng() -> f64 {
-    let mut val: [u8; 1] = [0];
-    getrandom(&mut val).unwrap();
-    val[0] as f64 / u8::MAX as f64
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::games::fixtures::two_team_match;
+    use proptest::prelude::{any, prop, Just, ProptestConfig};
+    use proptest::strategy::Strategy as PropStrategy;
+    use proptest::{prop_assert, prop_assert_eq, prop_oneof, proptest};
+    use rand::SeedableRng;
+
+    fn setup(ratings: &[f64]) -> (HashMap<u16, Player<RatingType>>, Session) {
+        let players = ratings
+            .iter()
+            .enumerate()
+            .map(|(id, rating)| {
+                (
+                    id as u16,
+                    Player {
+                        name: id.to_string(),
+                        rating: RatingType::from((*rating, 8.0)),
+                        wins: 0,
+                        losses: 0,
+                        draws: 0,
+                        initial_score: None,
+                        archived: false,
+                    },
+                )
+            })
+            .collect();
+        let session = Session {
+            id: 0,
+            players: (0..ratings.len() as u16).map(|id| (id, 0)).collect(),
+            most_played: 0,
+            pending: vec![],
+            next_pending_id: 0,
+            teammates: HashMap::new(),
+            opponents: HashMap::new(),
+        };
+
+        (players, session)
+    }
+
+    fn game_info(strategy: Strategy, seed: u64) -> GameInfo {
+        GameInfo {
+            games: 2,
+            players_per_team: 2,
+            stability: 10.0,
+            team2_players: None,
+            strategy,
+            seed: Some(seed),
+            repeat_penalty: 0.0,
+            keep_together: vec![],
+            keep_apart: vec![],
+        }
+    }
+
+    fn generate_with(game_info: GameInfo) -> std::result::Result<Vec<Game>, MatchmakingError> {
+        let ratings = [35.0, 31.0, 28.0, 27.0, 25.0, 24.0, 21.0, 18.0, 15.0, 12.0];
+        let (players, session) = setup(&ratings);
+        let system = RatingSystem::TrueSkill;
+        let seed = game_info.seed.unwrap();
+
+        generate_matches(
+            (0..ratings.len() as u16).collect(),
+            &players,
+            session,
+            game_info,
+            system,
+            &Settings::new(system),
+            &mut MatchRng::seed_from_u64(seed),
+        )
+    }
+
+    fn generate(strategy: Strategy, seed: u64) -> Vec<Game> {
+        generate_with(game_info(strategy, seed)).unwrap()
+    }
+
+    #[test]
+    fn test_generate_matches_seeded() {
+        for strategy in [Strategy::Greedy, Strategy::Balanced] {
+            for seed in 0..20 {
+                let games = generate(strategy, seed);
+                assert_eq!(games.len(), 2);
+
+                let mut ids: Vec<u16> = vec![];
+                for game in &games {
+                    assert_eq!(game.team1.len(), 2);
+                    assert_eq!(game.team2.len(), 2);
+                    ids.extend(game.team1.iter().chain(&game.team2));
+                }
+                ids.sort();
+                ids.dedup();
+                assert_eq!(ids.len(), 8);
+
+                let again = generate(strategy, seed);
+                for (a, b) in games.iter().zip(&again) {
+                    assert_eq!(a.team1, b.team1);
+                    assert_eq!(a.team2, b.team2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_matches_uneven() {
+        for strategy in [Strategy::Greedy, Strategy::Balanced] {
+            let games = generate_with(GameInfo {
+                team2_players: Some(3),
+                ..game_info(strategy, 0)
+            })
+            .unwrap();
+
+            assert_eq!(games.len(), 2);
+            for game in &games {
+                assert_eq!(game.team1.len(), 2);
+                assert_eq!(game.team2.len(), 3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_matches_repeat_penalty() {
+        let ratings = [30.0, 28.0, 26.0, 24.0];
+        let (players, mut session) = setup(&ratings);
+        let system = RatingSystem::TrueSkill;
+        let settings = Settings::new(system);
+
+        // 0 and 3 were teammates, splitting them costs about 8% in win chance
+        let played = two_team_match(0, vec![0, 3], vec![9]);
+        session.add_pairs(&played);
+
+        let generate = |strategy: Strategy, repeat_penalty: f64| -> Game {
+            let games = generate_matches(
+                (0..ratings.len() as u16).collect(),
+                &players,
+                session.clone(),
+                GameInfo {
+                    games: 1,
+                    repeat_penalty,
+                    ..game_info(strategy, 0)
+                },
+                system,
+                &settings,
+                &mut MatchRng::seed_from_u64(0),
+            )
+            .unwrap();
+            games[0].clone()
+        };
+        let together = |game: &Game| {
+            [&game.team1, &game.team2]
+                .iter()
+                .any(|team| team.contains(&0) && team.contains(&3))
+        };
+
+        for (repeat_penalty, repeat) in [(0.01, true), (0.2, false)] {
+            let greedy = generate(Strategy::Greedy, repeat_penalty);
+            let balanced = generate(Strategy::Balanced, repeat_penalty);
+            assert_eq!(greedy, balanced);
+            assert_eq!(together(&balanced), repeat);
+        }
+    }
+
+    #[test]
+    fn test_strategy_used() {
+        let players: Vec<u16> = (0..10).collect();
+        let greedy = game_info(Strategy::Greedy, 0);
+        assert_eq!(greedy.strategy_used(&players), Strategy::Greedy);
+        assert_eq!(
+            game_info(Strategy::Balanced, 0).strategy_used(&players),
+            Strategy::Balanced
+        );
+
+        for game_info in [
+            GameInfo {
+                repeat_penalty: 0.1,
+                ..greedy.clone()
+            },
+            GameInfo {
+                team2_players: Some(3),
+                ..greedy.clone()
+            },
+            GameInfo {
+                keep_apart: vec![[0, 1]],
+                ..greedy.clone()
+            },
+        ] {
+            assert_eq!(game_info.strategy_used(&players), Strategy::Balanced);
+        }
+
+        // Constraints on players who aren't taking part don't change the strategy
+        let game_info = GameInfo {
+            keep_together: vec![vec![0, 12]],
+            ..greedy
+        };
+        assert_eq!(game_info.strategy_used(&players), Strategy::Greedy);
+    }
+
+    #[test]
+    fn test_generate_matches_constraints() {
+        for strategy in [Strategy::Greedy, Strategy::Balanced] {
+            for seed in 0..10 {
+                // 9 has played the least but has to play with 8
+                let games = generate_with(GameInfo {
+                    keep_together: vec![vec![0, 1], vec![8, 9]],
+                    keep_apart: vec![[2, 3], [4, 5]],
+                    ..game_info(strategy, seed)
+                })
+                .unwrap();
+
+                let mut playing = 0;
+                for game in &games {
+                    for team in [&game.team1, &game.team2] {
+                        assert_eq!(team.contains(&0), team.contains(&1));
+                        assert_eq!(team.contains(&8), team.contains(&9));
+                        assert!(!(team.contains(&2) && team.contains(&3)));
+                        assert!(!(team.contains(&4) && team.contains(&5)));
+                        playing += team.len();
+                    }
+                }
+                assert_eq!(playing, 8);
+            }
+        }
+
+        assert_eq!(
+            generate_with(GameInfo {
+                keep_together: vec![vec![0, 1, 2]],
+                ..game_info(Strategy::Greedy, 0)
+            }),
+            Err(MatchmakingError::GroupTooLarge {
+                players: vec![0, 1, 2]
+            })
+        );
+        assert_eq!(
+            generate_with(GameInfo {
+                keep_together: vec![vec![0, 1]],
+                keep_apart: vec![[1, 0]],
+                ..game_info(Strategy::Greedy, 0)
+            }),
+            Err(MatchmakingError::ConflictingConstraints {
+                players: vec![1, 0]
+            })
+        );
+        assert_eq!(
+            generate_with(GameInfo {
+                games: 1,
+                players_per_team: 3,
+                keep_together: vec![vec![0, 1], vec![2, 3], vec![4, 5], vec![6, 7], vec![8, 9]],
+                ..game_info(Strategy::Balanced, 0)
+            }),
+            Err(MatchmakingError::Unsatisfiable)
+        );
+    }
+
+    #[test]
+    fn test_generate_matches_errors() {
+        let invalid = |game_info: GameInfo| generate_with(game_info).unwrap_err();
+
+        assert_eq!(
+            invalid(GameInfo {
+                games: 0,
+                ..game_info(Strategy::Greedy, 0)
+            }),
+            MatchmakingError::NoGames
+        );
+        assert_eq!(
+            invalid(GameInfo {
+                players_per_team: 0,
+                ..game_info(Strategy::Greedy, 0)
+            }),
+            MatchmakingError::EmptyTeam
+        );
+        assert_eq!(
+            invalid(GameInfo {
+                team2_players: Some(0),
+                ..game_info(Strategy::Balanced, 0)
+            }),
+            MatchmakingError::EmptyTeam
+        );
+        assert_eq!(
+            invalid(GameInfo {
+                players_per_team: 6,
+                ..game_info(Strategy::Greedy, 0)
+            }),
+            MatchmakingError::NotEnoughPlayers {
+                needed: 12,
+                available: 10
+            }
+        );
+        assert_eq!(
+            invalid(GameInfo {
+                stability: -1.0,
+                ..game_info(Strategy::Greedy, 0)
+            }),
+            MatchmakingError::InvalidStability
+        );
+        for repeat_penalty in [-0.1, 0.6, f64::NAN] {
+            assert_eq!(
+                invalid(GameInfo {
+                    repeat_penalty,
+                    ..game_info(Strategy::Balanced, 0)
+                }),
+                MatchmakingError::InvalidRepeatPenalty
+            );
+        }
+
+        let (players, session) = setup(&[25.0, 25.0, 25.0, 25.0]);
+        let system = RatingSystem::TrueSkill;
+        let generate = |participants: Vec<u16>| {
+            generate_matches(
+                participants,
+                &players,
+                session.clone(),
+                game_info(Strategy::Greedy, 0),
+                system,
+                &Settings::new(system),
+                &mut MatchRng::seed_from_u64(0),
+            )
+        };
+        assert_eq!(
+            generate(vec![0, 1, 2, 7]),
+            Err(MatchmakingError::UnknownPlayers { players: vec![7] })
+        );
+        assert_eq!(
+            generate(vec![0, 1, 1, 2]),
+            Err(MatchmakingError::DuplicatePlayers { players: vec![1] })
+        );
+        assert_eq!(
+            generate(vec![1, 2, 1, 2, 1]),
+            Err(MatchmakingError::DuplicatePlayers {
+                players: vec![1, 2]
+            })
+        );
+
+        // Every rating being the same used to give NaN probabilities
+        let games = generate(vec![0, 1, 2, 3]).unwrap();
+        assert_eq!(games.len(), 1);
+    }
+
+    fn game_info_strategy() -> impl PropStrategy<Value = GameInfo> {
+        (
+            0..4usize,
+            0..4usize,
+            proptest::option::of(0..4usize),
+            0.0..20.0f64,
+            prop_oneof![Just(Strategy::Greedy), Just(Strategy::Balanced)],
+            any::<u64>(),
+            0.0..0.5f64,
+        )
+            .prop_map(
+                |(games, players_per_team, team2_players, stability, strategy, seed, penalty)| {
+                    GameInfo {
+                        games,
+                        players_per_team,
+                        stability,
+                        team2_players,
+                        strategy,
+                        seed: Some(seed),
+                        repeat_penalty: penalty,
+                        keep_together: vec![],
+                        keep_apart: vec![],
+                    }
+                },
+            )
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_generate_matches(
+            ratings in prop::collection::vec(prop_oneof![Just(25.0), 0.0..50.0f64], 0..20),
+            played in prop::collection::vec(0..3u16, 20),
+            game_info in game_info_strategy(),
+        ) {
+            let (players, mut session) = setup(&ratings);
+            // Play counts are left ahead of most played to check they are handled
+            session.players.iter_mut().for_each(|(id, count)| *count = played[*id as usize]);
+            let participants: Vec<u16> = (0..ratings.len() as u16).collect();
+            let (team1, team2) = game_info.team_sizes();
+            let system = RatingSystem::TrueSkill;
+
+            let generate = || {
+                generate_matches(
+                    participants.clone(),
+                    &players,
+                    session.clone(),
+                    game_info.clone(),
+                    system,
+                    &Settings::new(system),
+                    &mut MatchRng::seed_from_u64(game_info.seed.unwrap()),
+                )
+            };
+
+            match generate() {
+                Ok(games) => {
+                    prop_assert!(game_info.games > 0 && team1 > 0 && team2 > 0);
+                    prop_assert_eq!(
+                        games.len(),
+                        cmp::min(game_info.games, ratings.len() / (team1 + team2))
+                    );
+
+                    let mut ids: Vec<u16> = vec![];
+                    for game in &games {
+                        prop_assert_eq!(game.team1.len(), team1);
+                        prop_assert_eq!(game.team2.len(), team2);
+                        ids.extend(game.team1.iter().chain(&game.team2));
+                    }
+                    let count = ids.len();
+                    ids.sort();
+                    ids.dedup();
+                    prop_assert_eq!(ids.len(), count);
+                    prop_assert!(ids.iter().all(|id| participants.contains(id)));
+
+                    prop_assert_eq!(generate().unwrap(), games);
+                }
+                Err(MatchmakingError::NoGames) => prop_assert_eq!(game_info.games, 0),
+                Err(MatchmakingError::EmptyTeam) => prop_assert!(team1 == 0 || team2 == 0),
+                Err(MatchmakingError::NotEnoughPlayers { needed, available }) => {
+                    prop_assert_eq!(needed, team1 + team2);
+                    prop_assert_eq!(available, ratings.len());
+                    prop_assert!(available < needed);
+                }
+                Err(err) => prop_assert!(false, "unexpected error {:?}", err),
+            }
+        }
+
+        #[test]
+        fn prop_get_prob(
+            player in 0.0..50.0f64,
+            others in prop::collection::vec(prop_oneof![Just(25.0), 0.0..50.0f64], 1..10),
+            stability in 0.0..20.0f64,
+        ) {
+            let probs = get_prob(player, others.clone(), stability);
+
+            prop_assert_eq!(probs.len(), others.len());
+            prop_assert!(probs.iter().all(|prob| prob.is_finite()));
+            prop_assert!(probs.windows(2).all(|pair| pair[0] <= pair[1]));
+            prop_assert!((probs[probs.len() - 1] - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_get_prob_equal() {
+        assert_eq!(get_prob(25.0, vec![25.0, 25.0], 10.0), vec![0.5, 1.0]);
+        assert_eq!(get_prob(20.0, vec![25.0], 10.0), vec![1.0]);
+    }
 
     #[test]
     fn test_get_prob() {
@@ -1,63 +1,399 @@
 pub mod matchmaking;
+pub mod rating;
+pub mod stats;
 
-use crate::rankings::{Client, Empty, Match, Player};
+use crate::rankings::{Match, Player, RatingChange, Settings};
+use crate::RatingType;
+use rating::{margin_multiplier, RatingSystem};
 
 use std::collections::HashMap;
 
-use skillratings::{Outcomes, TeamRatingSystem};
-use worker::*;
+use serde::{Deserialize, Serialize};
+use skillratings::Outcomes;
 
-pub async fn add_match<RS: TeamRatingSystem>(
-    winners: &[u16],
-    losers: &[u16],
-    client: Client,
-    rating_system: RS,
-) -> Result<()> {
-    let mut players: HashMap<u16, Player<RS::RATING>> =
-        client.fetch("/players", "", Method::Get).await?;
-
-    let winners_ratings: Vec<RS::RATING> = winners
+/// Updates the ratings and results of every player in the match, returning each player's rating
+/// before and after
+pub fn rate_match(
+    players: &mut HashMap<u16, Player<RatingType>>,
+    m: &Match,
+    rating_system: RatingSystem,
+    settings: &Settings,
+) -> Vec<RatingChange> {
+    let mut changes = vec![];
+    let ratings: Vec<Vec<RatingType>> = m
+        .teams
         .iter()
-        .map(|winner| players.get(winner).unwrap().rating)
+        .map(|team| {
+            team.players
+                .iter()
+                .map(|player| players.get(player).unwrap().rating)
+                .collect()
+        })
         .collect();
-    let losers_ratings: Vec<RS::RATING> = losers
+    let teams: Vec<(&[RatingType], usize)> = ratings
         .iter()
-        .map(|loser| players.get(loser).unwrap().rating)
+        .zip(m.teams.iter())
+        .map(|(ratings, team)| (ratings.as_slice(), team.place))
         .collect();
 
-    let (winners_final, losers_final) =
-        rating_system.rate(&winners_ratings, &losers_ratings, &Outcomes::WIN);
+    let teams_final = rating_system.rate(settings, &teams);
+    let multiplier = margin_multiplier(&m.scores(), settings.margin_weight);
 
-    winners
+    m.teams
         .iter()
-        .zip(winners_final.iter())
-        .for_each(|(id, rating)| {
-            let player = players.get_mut(id).unwrap();
-            player.rating = *rating;
-            player.wins += 1;
+        .zip(teams_final.iter())
+        .for_each(|(team, team_final)| {
+            let outcome = m.outcome(team);
+            team.players
+                .iter()
+                .zip(team_final.iter())
+                .for_each(|(id, rating)| {
+                    let player = players.get_mut(id).unwrap();
+                    let before = player.rating;
+                    let change = (rating.rating - player.rating.rating) * multiplier;
+                    player.rating = RatingType {
+                        rating: player.rating.rating + change,
+                        ..*rating
+                    };
+                    changes.push(RatingChange {
+                        player: *id,
+                        before,
+                        after: player.rating,
+                    });
+                    match outcome {
+                        Outcomes::WIN => player.wins += 1,
+                        Outcomes::DRAW => player.draws += 1,
+                        Outcomes::LOSS => player.losses += 1,
+                    }
+                });
         });
-    losers
+
+    changes
+}
+
+/// Players created before initial scores were kept have none, even if they were created with a
+/// custom score. Players without matches still have the rating they were created with, so it
+/// becomes their initial score instead of being reset to the default when ratings are replayed.
+pub fn backfill_initial_scores(
+    players: &mut HashMap<u16, Player<RatingType>>,
+    settings: &Settings,
+) {
+    players
+        .values_mut()
+        .filter(|player| player.initial_score.is_none())
+        .filter(|player| player.wins + player.losses + player.draws == 0)
+        .filter(|player| player.rating.rating != settings.initial_rating)
+        .for_each(|player| player.initial_score = Some(player.rating.rating.round() as i32));
+}
+
+/// Resets every player to their initial rating and rates all matches again in order, returning
+/// the rating changes of each match
+pub fn replay(
+    players: &mut HashMap<u16, Player<RatingType>>,
+    matches: &[Match],
+    rating_system: RatingSystem,
+    settings: &Settings,
+) -> Vec<Vec<RatingChange>> {
+    players.values_mut().for_each(|player| {
+        player.rating = settings.initial_rating(player.initial_score.map(|score| score as f64));
+        player.wins = 0;
+        player.losses = 0;
+        player.draws = 0;
+    });
+
+    matches
         .iter()
-        .zip(losers_final.iter())
-        .for_each(|(id, rating)| {
-            let player = players.get_mut(id).unwrap();
-            player.rating = *rating;
-            player.losses += 1;
-        });
+        .map(|m| rate_match(players, m, rating_system, settings))
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub(crate) match_id: u16,
+    pub(crate) played_at: u64,
+    pub(crate) before: RatingType,
+    pub(crate) after: RatingType,
+}
+
+/// Rating of the player before and after each of their matches, oldest first. History isn't
+/// stored with the matches so it is rebuilt by rating every match again.
+pub fn rating_history(
+    players: &HashMap<u16, Player<RatingType>>,
+    matches: &[Match],
+    rating_system: RatingSystem,
+    settings: &Settings,
+    player: u16,
+) -> Vec<HistoryEntry> {
+    let mut players = players.clone();
+    backfill_initial_scores(&mut players, settings);
+    let changes = replay(&mut players, matches, rating_system, settings);
 
-    let participants: Vec<u16> = [winners, losers].concat();
-    let _: Empty = client
-        .fetch("/session", &participants, Method::Post)
-        .await?;
+    matches
+        .iter()
+        .zip(changes)
+        .filter_map(|(m, changes)| {
+            changes
+                .into_iter()
+                .find(|change| change.player == player)
+                .map(|change| HistoryEntry {
+                    match_id: m.id,
+                    played_at: m.played_at,
+                    before: change.before,
+                    after: change.after,
+                })
+        })
+        .collect()
+}
 
-    let _: Empty = client.fetch("/players", &players, Method::Put).await?;
+/// Expected result of a match between two teams
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Prediction {
+    pub(crate) team1: f64, // Probability of team 1 winning
+    pub(crate) team2: f64,
+    pub(crate) quality: Option<f64>, // TrueSkill match quality, only for TrueSkill style ratings
+}
 
-    let m = Match {
-        id: 0,
-        team1: winners.to_vec(),
-        team2: losers.to_vec(),
+pub fn predict(
+    players: &HashMap<u16, Player<RatingType>>,
+    team1: &[u16],
+    team2: &[u16],
+    rating_system: RatingSystem,
+    settings: &Settings,
+) -> Prediction {
+    let ratings = |team: &[u16]| -> Vec<RatingType> {
+        team.iter()
+            .map(|player| players.get(player).unwrap().rating)
+            .collect()
     };
-    let _: Empty = client.fetch("/matches", &m, Method::Post).await?;
+    let (team1, team2) = (ratings(team1), ratings(team2));
+    let (team1_win, team2_win) = rating_system.expected_score(settings, &team1, &team2);
+
+    Prediction {
+        team1: team1_win,
+        team2: team2_win,
+        quality: rating_system.match_quality(settings, &team1, &team2),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RatingDiff {
+    pub(crate) id: u16,
+    pub(crate) name: String,
+    pub(crate) before: RatingType,
+    pub(crate) after: RatingType,
+    pub(crate) default_initial: bool, // Player has no initial score so started from the default rating
+}
+
+/// Rating of every player before and after a change, highest new rating first
+pub fn rating_diff(
+    before: &HashMap<u16, Player<RatingType>>,
+    after: &HashMap<u16, Player<RatingType>>,
+) -> Vec<RatingDiff> {
+    let mut diff: Vec<RatingDiff> = after
+        .iter()
+        .filter_map(|(id, player)| {
+            before.get(id).map(|old| RatingDiff {
+                id: *id,
+                name: player.name.clone(),
+                before: old.rating,
+                after: player.rating,
+                default_initial: player.initial_score.is_none(),
+            })
+        })
+        .collect();
+    diff.sort_by(|a, b| b.after.rating.total_cmp(&a.after.rating));
+
+    diff
+}
+
+/// Players and matches for the tests of every games module
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+    use crate::rankings::MatchRecord;
+
+    pub(crate) fn player(name: &str, settings: &Settings) -> Player<RatingType> {
+        Player {
+            name: name.to_string(),
+            rating: settings.initial_rating(None),
+            wins: 0,
+            losses: 0,
+            draws: 0,
+            initial_score: None,
+            archived: false,
+        }
+    }
+
+    pub(crate) fn two_team_match(id: u16, winners: Vec<u16>, losers: Vec<u16>) -> Match {
+        MatchRecord::TwoTeams {
+            id,
+            team1: winners,
+            team2: losers,
+            outcome: Default::default(),
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{player, two_team_match};
+    use super::*;
+
+    #[test]
+    fn test_backfill_initial_scores() {
+        let system = RatingSystem::Elo;
+        let settings = Settings::new(system);
+        let custom = |wins| Player {
+            rating: settings.initial_rating(Some(1200.0)),
+            wins,
+            ..player("", &settings)
+        };
+        let mut players: HashMap<u16, Player<RatingType>> =
+            HashMap::from([(0, custom(0)), (1, custom(1)), (2, player("", &settings))]);
+
+        backfill_initial_scores(&mut players, &settings);
+        assert_eq!(players[&0].initial_score, Some(1200));
+        assert_eq!(players[&1].initial_score, None);
+        assert_eq!(players[&2].initial_score, None);
+
+        replay(&mut players, &[], system, &settings);
+        assert_eq!(players[&0].rating.rating, 1200.0);
+    }
+
+    #[test]
+    fn test_replay() {
+        let system = RatingSystem::TrueSkill;
+        let settings = Settings::new(system);
+        let mut players: HashMap<u16, Player<RatingType>> = (0..4)
+            .map(|id| (id, player(&id.to_string(), &settings)))
+            .collect();
+
+        let matches = vec![
+            two_team_match(0, vec![0, 1], vec![2, 3]),
+            two_team_match(1, vec![0, 2], vec![1, 3]),
+            two_team_match(2, vec![3], vec![0]),
+        ];
+        matches.iter().for_each(|m| {
+            rate_match(&mut players, m, system, &settings);
+        });
+        let incremental = players.clone();
+
+        replay(&mut players, &matches, system, &settings);
+        for (id, player) in players.iter() {
+            assert_eq!(player.rating, incremental[id].rating);
+            assert_eq!(player.wins, incremental[id].wins);
+            assert_eq!(player.losses, incremental[id].losses);
+        }
+        assert_eq!(players[&0].wins, 2);
+        assert_eq!(players[&0].losses, 1);
+
+        // Removing the last match restores the ratings from before it was played
+        let mut expected = (0..4)
+            .map(|id| (id, player(&id.to_string(), &settings)))
+            .collect();
+        replay(&mut expected, &matches[..2], system, &settings);
+        replay(&mut players, &matches[..2], system, &settings);
+        for (id, player) in players.iter() {
+            assert_eq!(player.rating, expected[id].rating);
+        }
+        assert_eq!(players[&3].wins, 0);
+        assert_eq!(players[&0].losses, 0);
+    }
+
+    #[test]
+    fn test_rate_match_uneven() {
+        for system in [
+            RatingSystem::TrueSkill,
+            RatingSystem::WengLin,
+            RatingSystem::Glicko2,
+            RatingSystem::Elo,
+        ] {
+            let settings = Settings::new(system);
+            let initial: HashMap<u16, Player<RatingType>> = (0..5)
+                .map(|id| (id, player(&id.to_string(), &settings)))
+                .collect();
+            let start = initial[&0].rating.rating;
+
+            // Two players beating three
+            let mut players = initial.clone();
+            let changes = rate_match(
+                &mut players,
+                &two_team_match(0, vec![0, 1], vec![2, 3, 4]),
+                system,
+                &settings,
+            );
+            assert_eq!(changes.len(), 5);
+            for id in [0, 1] {
+                assert!(players[&id].rating.rating > start);
+                assert_eq!(players[&id].wins, 1);
+            }
+            for id in [2, 3, 4] {
+                assert!(players[&id].rating.rating < start);
+                assert_eq!(players[&id].losses, 1);
+            }
+            let upset_gain = players[&0].rating.rating - start;
+
+            let mut players = initial.clone();
+            rate_match(
+                &mut players,
+                &two_team_match(0, vec![2, 3, 4], vec![0, 1]),
+                system,
+                &settings,
+            );
+            let expected_gain = players[&2].rating.rating - start;
+
+            // Elo and Glicko-2 compare teams of different sizes by their combined strength, so like
+            // TrueSkill and Weng-Lin they expect the bigger team to win
+            assert!(upset_gain > expected_gain);
+        }
+    }
+
+    #[test]
+    fn test_predict() {
+        let system = RatingSystem::TrueSkill;
+        let settings = Settings::new(system);
+        let mut players: HashMap<u16, Player<RatingType>> = (0..4)
+            .map(|id| (id, player(&id.to_string(), &settings)))
+            .collect();
+        players.get_mut(&0).unwrap().rating.rating = 35.0;
+
+        let prediction = predict(&players, &[0, 1], &[2, 3], system, &settings);
+        assert!(prediction.team1 > prediction.team2);
+        let even = predict(&players, &[0, 2], &[1, 3], system, &settings);
+        assert!(even.quality.unwrap() < 1.0);
+
+        players.get_mut(&2).unwrap().rating.rating = 35.0;
+        let balanced = predict(&players, &[0, 1], &[2, 3], system, &settings);
+        assert!(balanced.quality.unwrap() > prediction.quality.unwrap());
+    }
+
+    #[test]
+    fn test_rating_history() {
+        let system = RatingSystem::TrueSkill;
+        let settings = Settings::new(system);
+        let mut players: HashMap<u16, Player<RatingType>> = (0..3)
+            .map(|id| (id, player(&id.to_string(), &settings)))
+            .collect();
+
+        let matches = vec![
+            two_team_match(0, vec![0], vec![1]),
+            two_team_match(1, vec![1], vec![2]),
+            two_team_match(2, vec![2], vec![0]),
+        ];
+        let history = rating_history(&players, &matches, system, &settings, 0);
+        replay(&mut players, &matches, system, &settings);
+
+        assert_eq!(
+            history.iter().map(|h| h.match_id).collect::<Vec<u16>>(),
+            vec![0, 2]
+        );
+        assert_eq!(history[0].before, settings.initial_rating(None));
+        assert!(history[0].after.rating > history[0].before.rating);
+        assert_eq!(history[1].before, history[0].after);
+        assert!(history[1].after.rating < history[1].before.rating);
+        assert_eq!(history[1].after, players[&0].rating);
 
-    Ok(())
+        assert!(rating_history(&players, &matches, system, &settings, 7).is_empty());
+    }
 }
@@ -0,0 +1,649 @@
+mod teams;
+
+use crate::rankings::Settings;
+use teams::{EloTeams, Glicko2Teams};
+
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+use skillratings::elo::{EloConfig, EloRating};
+use skillratings::glicko2::{Glicko2Config, Glicko2Rating};
+use skillratings::trueskill::{
+    match_quality_two_teams, TrueSkill, TrueSkillConfig, TrueSkillRating,
+};
+use skillratings::weng_lin::{WengLin, WengLinConfig, WengLinRating};
+use skillratings::{MultiTeamOutcome, MultiTeamRatingSystem, Outcomes, Rating, TeamRatingSystem};
+
+/// Rating stored for each player. Every supported rating system can be represented with a rating
+/// and an uncertainty, Glicko-2 additionally tracks the volatility of a player.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PlayerRating {
+    pub(crate) rating: f64,
+    pub(crate) uncertainty: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) volatility: Option<f64>,
+}
+
+impl PlayerRating {
+    /// Rating the player is very likely above, `k` is the number of standard deviations
+    pub fn conservative(&self, k: f64) -> f64 {
+        self.rating - k * self.uncertainty
+    }
+
+    /// Grows the uncertainty like TrueSkill dynamics would over `days` of inactivity, never above
+    /// `max_uncertainty`
+    pub fn decayed(&self, days: f64, per_day: f64, max_uncertainty: f64) -> PlayerRating {
+        let uncertainty = (self.uncertainty.powi(2) + days * per_day.powi(2)).sqrt();
+
+        PlayerRating {
+            uncertainty: uncertainty.min(max_uncertainty.max(self.uncertainty)),
+            ..*self
+        }
+    }
+}
+
+/// How players are ordered on the leaderboard. Conservative ordering only ranks players highly
+/// once the rating system is confident in their rating.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RankingMode {
+    #[default]
+    Rating,
+    Conservative,
+}
+
+impl Rating for PlayerRating {
+    fn rating(&self) -> f64 {
+        self.rating
+    }
+
+    fn uncertainty(&self) -> Option<f64> {
+        Some(self.uncertainty)
+    }
+
+    fn new(rating: Option<f64>, uncertainty: Option<f64>) -> Self {
+        PlayerRating {
+            rating: rating.unwrap_or(25.0),
+            uncertainty: uncertainty.unwrap_or(25.0 / 3.0),
+            volatility: None,
+        }
+    }
+}
+
+impl From<(f64, f64)> for PlayerRating {
+    fn from((rating, uncertainty): (f64, f64)) -> Self {
+        PlayerRating {
+            rating,
+            uncertainty,
+            volatility: None,
+        }
+    }
+}
+
+impl From<PlayerRating> for TrueSkillRating {
+    fn from(r: PlayerRating) -> Self {
+        TrueSkillRating {
+            rating: r.rating,
+            uncertainty: r.uncertainty,
+        }
+    }
+}
+
+impl From<TrueSkillRating> for PlayerRating {
+    fn from(r: TrueSkillRating) -> Self {
+        PlayerRating::from((r.rating, r.uncertainty))
+    }
+}
+
+impl From<PlayerRating> for WengLinRating {
+    fn from(r: PlayerRating) -> Self {
+        WengLinRating {
+            rating: r.rating,
+            uncertainty: r.uncertainty,
+        }
+    }
+}
+
+impl From<WengLinRating> for PlayerRating {
+    fn from(r: WengLinRating) -> Self {
+        PlayerRating::from((r.rating, r.uncertainty))
+    }
+}
+
+impl From<PlayerRating> for Glicko2Rating {
+    fn from(r: PlayerRating) -> Self {
+        Glicko2Rating {
+            rating: r.rating,
+            deviation: r.uncertainty,
+            volatility: r.volatility.unwrap_or(Glicko2Rating::new().volatility),
+        }
+    }
+}
+
+impl From<Glicko2Rating> for PlayerRating {
+    fn from(r: Glicko2Rating) -> Self {
+        PlayerRating {
+            rating: r.rating,
+            uncertainty: r.deviation,
+            volatility: Some(r.volatility),
+        }
+    }
+}
+
+impl From<PlayerRating> for EloRating {
+    fn from(r: PlayerRating) -> Self {
+        EloRating { rating: r.rating }
+    }
+}
+
+impl From<EloRating> for PlayerRating {
+    fn from(r: EloRating) -> Self {
+        PlayerRating::from((r.rating, 0.0))
+    }
+}
+
+/// Rating system used by a leaderboard, chosen when the leaderboard is created
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RatingSystem {
+    #[default]
+    TrueSkill,
+    WengLin,
+    Glicko2,
+    Elo,
+}
+
+impl RatingSystem {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RatingSystem::TrueSkill => "TrueSkill",
+            RatingSystem::WengLin => "Weng-Lin",
+            RatingSystem::Glicko2 => "Glicko-2",
+            RatingSystem::Elo => "Elo",
+        }
+    }
+
+    /// Rates a match between any number of teams, each team is given with its place. Teams with a
+    /// lower place beat teams with a higher place and teams with the same place drew.
+    ///
+    /// Only Weng-Lin has multi team support in skillratings. Matches with more than two teams in
+    /// the other rating systems are rated with [`RatingSystem::rate_pairwise`] instead, which is an
+    /// approximation rather than part of the rating system itself.
+    pub fn rate(
+        &self,
+        settings: &Settings,
+        teams: &[(&[PlayerRating], usize)],
+    ) -> Vec<Vec<PlayerRating>> {
+        match teams {
+            [] | [_] => teams.iter().map(|(team, _)| team.to_vec()).collect(),
+            [(team1, place1), (team2, place2)] => {
+                let outcome = place_outcome(*place1, *place2);
+                let (team1_final, team2_final) =
+                    self.rate_two_teams(settings, team1, team2, &outcome);
+                vec![team1_final, team2_final]
+            }
+            _ => match self {
+                RatingSystem::WengLin => {
+                    let rating_system =
+                        <WengLin as MultiTeamRatingSystem>::new(weng_lin_config(settings));
+                    rate_multi_team(&rating_system, teams)
+                }
+                _ => self.rate_pairwise(settings, teams),
+            },
+        }
+    }
+
+    /// Rates a match between two teams, outcome is from the perspective of `team1`
+    fn rate_two_teams(
+        &self,
+        settings: &Settings,
+        team1: &[PlayerRating],
+        team2: &[PlayerRating],
+        outcome: &Outcomes,
+    ) -> (Vec<PlayerRating>, Vec<PlayerRating>) {
+        match self {
+            RatingSystem::TrueSkill => {
+                let rating_system = TrueSkill::new(trueskill_config(settings));
+                rate_teams(&rating_system, team1, team2, outcome)
+            }
+            RatingSystem::WengLin => {
+                let rating_system = <WengLin as TeamRatingSystem>::new(weng_lin_config(settings));
+                rate_teams(&rating_system, team1, team2, outcome)
+            }
+            RatingSystem::Glicko2 => {
+                let rating_system = Glicko2Teams::new(Glicko2Config::new());
+                rate_teams(&rating_system, team1, team2, outcome)
+            }
+            RatingSystem::Elo => {
+                let rating_system = EloTeams::new(EloConfig::new());
+                rate_teams(&rating_system, team1, team2, outcome)
+            }
+        }
+    }
+
+    /// Probability of each team winning a match between two teams
+    pub fn expected_score(
+        &self,
+        settings: &Settings,
+        team1: &[PlayerRating],
+        team2: &[PlayerRating],
+    ) -> (f64, f64) {
+        match self {
+            RatingSystem::TrueSkill => {
+                let rating_system = TrueSkill::new(trueskill_config(settings));
+                expected_teams(&rating_system, team1, team2)
+            }
+            RatingSystem::WengLin => {
+                let rating_system = <WengLin as TeamRatingSystem>::new(weng_lin_config(settings));
+                expected_teams(&rating_system, team1, team2)
+            }
+            RatingSystem::Glicko2 => {
+                let rating_system = Glicko2Teams::new(Glicko2Config::new());
+                expected_teams(&rating_system, team1, team2)
+            }
+            RatingSystem::Elo => {
+                let rating_system = EloTeams::new(EloConfig::new());
+                expected_teams(&rating_system, team1, team2)
+            }
+        }
+    }
+
+    /// TrueSkill match quality, the probability of a draw between the two teams. Only rating
+    /// systems with TrueSkill style ratings have a match quality.
+    pub fn match_quality(
+        &self,
+        settings: &Settings,
+        team1: &[PlayerRating],
+        team2: &[PlayerRating],
+    ) -> Option<f64> {
+        match self {
+            RatingSystem::TrueSkill | RatingSystem::WengLin => {
+                let team1: Vec<TrueSkillRating> = team1.iter().map(|r| (*r).into()).collect();
+                let team2: Vec<TrueSkillRating> = team2.iter().map(|r| (*r).into()).collect();
+                Some(match_quality_two_teams(
+                    &team1,
+                    &team2,
+                    &trueskill_config(settings),
+                ))
+            }
+            RatingSystem::Glicko2 | RatingSystem::Elo => None,
+        }
+    }
+
+    /// Rating systems without multi team support rate every pair of teams as a separate two team
+    /// match against the ratings from before the match, each player receives the average of their
+    /// rating changes
+    fn rate_pairwise(
+        &self,
+        settings: &Settings,
+        teams: &[(&[PlayerRating], usize)],
+    ) -> Vec<Vec<PlayerRating>> {
+        let opponents = (teams.len() - 1) as f64;
+
+        teams
+            .iter()
+            .enumerate()
+            .map(|(i, (team, place))| {
+                let mut team_final = team.to_vec();
+
+                teams.iter().enumerate().filter(|(j, _)| i != *j).for_each(
+                    |(_, (other, other_place))| {
+                        let outcome = place_outcome(*place, *other_place);
+                        let (rated, _) = self.rate_two_teams(settings, team, other, &outcome);
+
+                        team_final
+                            .iter_mut()
+                            .zip(team.iter().zip(rated.iter()))
+                            .for_each(|(player, (before, after))| {
+                                player.rating += (after.rating - before.rating) / opponents;
+                                player.uncertainty +=
+                                    (after.uncertainty - before.uncertainty) / opponents;
+                                if let Some(after_volatility) = after.volatility {
+                                    let before_volatility = Glicko2Rating::from(*before).volatility;
+                                    let volatility = player.volatility.unwrap_or(before_volatility);
+                                    player.volatility = Some(
+                                        volatility
+                                            + (after_volatility - before_volatility) / opponents,
+                                    );
+                                }
+                            });
+                    },
+                );
+
+                team_final
+            })
+            .collect()
+    }
+}
+
+/// Multiplier for rating changes based on the margin of victory. The margin is the difference
+/// between the highest and lowest score relative to their total, so a 21-19 game barely changes
+/// the rating update while a 21-3 game increases it by most of `weight`.
+pub fn margin_multiplier(scores: &[Option<f64>], weight: f64) -> f64 {
+    let scores: Option<Vec<f64>> = scores.iter().copied().collect();
+
+    match scores {
+        Some(scores) if weight > 0.0 && scores.len() > 1 => {
+            let high = scores.iter().copied().fold(f64::MIN, f64::max);
+            let low = scores.iter().copied().fold(f64::MAX, f64::min);
+            let total = high.abs() + low.abs();
+
+            if total > 0.0 {
+                1.0 + weight * (high - low) / total
+            } else {
+                1.0
+            }
+        }
+        _ => 1.0,
+    }
+}
+
+fn trueskill_config(settings: &Settings) -> TrueSkillConfig {
+    TrueSkillConfig {
+        draw_probability: settings.draw_probability,
+        beta: settings.beta,
+        default_dynamics: settings.dynamics,
+    }
+}
+
+fn weng_lin_config(settings: &Settings) -> WengLinConfig {
+    WengLinConfig {
+        beta: settings.beta,
+        ..WengLinConfig::new()
+    }
+}
+
+fn place_outcome(place: usize, other_place: usize) -> Outcomes {
+    match place.cmp(&other_place) {
+        Ordering::Less => Outcomes::WIN,
+        Ordering::Equal => Outcomes::DRAW,
+        Ordering::Greater => Outcomes::LOSS,
+    }
+}
+
+fn rate_multi_team<RS: MultiTeamRatingSystem>(
+    rating_system: &RS,
+    teams: &[(&[PlayerRating], usize)],
+) -> Vec<Vec<PlayerRating>>
+where
+    RS::RATING: From<PlayerRating> + Into<PlayerRating>,
+{
+    let converted: Vec<Vec<RS::RATING>> = teams
+        .iter()
+        .map(|(team, _)| team.iter().map(|r| (*r).into()).collect())
+        .collect();
+    let teams_and_ranks: Vec<(&[RS::RATING], MultiTeamOutcome)> = converted
+        .iter()
+        .zip(teams.iter())
+        .map(|(team, (_, place))| (team.as_slice(), MultiTeamOutcome::new(*place)))
+        .collect();
+
+    rating_system
+        .rate(&teams_and_ranks)
+        .into_iter()
+        .map(|team| team.into_iter().map(|r| r.into()).collect())
+        .collect()
+}
+
+fn rate_teams<RS: TeamRatingSystem>(
+    rating_system: &RS,
+    team1: &[PlayerRating],
+    team2: &[PlayerRating],
+    outcome: &Outcomes,
+) -> (Vec<PlayerRating>, Vec<PlayerRating>)
+where
+    RS::RATING: From<PlayerRating> + Into<PlayerRating>,
+{
+    let team1: Vec<RS::RATING> = team1.iter().map(|r| (*r).into()).collect();
+    let team2: Vec<RS::RATING> = team2.iter().map(|r| (*r).into()).collect();
+
+    let (team1_final, team2_final) = rating_system.rate(&team1, &team2, outcome);
+
+    (
+        team1_final.into_iter().map(|r| r.into()).collect(),
+        team2_final.into_iter().map(|r| r.into()).collect(),
+    )
+}
+
+fn expected_teams<RS: TeamRatingSystem>(
+    rating_system: &RS,
+    team1: &[PlayerRating],
+    team2: &[PlayerRating],
+) -> (f64, f64)
+where
+    RS::RATING: From<PlayerRating>,
+{
+    let team1: Vec<RS::RATING> = team1.iter().map(|r| (*r).into()).collect();
+    let team2: Vec<RS::RATING> = team2.iter().map(|r| (*r).into()).collect();
+
+    rating_system.expected_score(&team1, &team2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYSTEMS: [RatingSystem; 4] = [
+        RatingSystem::TrueSkill,
+        RatingSystem::WengLin,
+        RatingSystem::Glicko2,
+        RatingSystem::Elo,
+    ];
+
+    #[test]
+    fn test_initial_rating() {
+        let settings = Settings::new(RatingSystem::TrueSkill);
+        assert_eq!(
+            settings.initial_rating(None),
+            PlayerRating::from((25.0, 25.0 / 3.0))
+        );
+        assert_eq!(
+            settings.initial_rating(Some(30.0)),
+            PlayerRating::from((30.0, 25.0 / 3.0))
+        );
+        assert_eq!(
+            Settings::new(RatingSystem::Elo).initial_rating(None),
+            PlayerRating::from((1000.0, 0.0))
+        );
+        assert_eq!(
+            Settings::new(RatingSystem::Glicko2).initial_rating(Some(1700.0)),
+            PlayerRating::from((1700.0, 350.0))
+        );
+    }
+
+    #[test]
+    fn test_rate_winners_gain() {
+        for system in SYSTEMS {
+            let settings = Settings::new(system);
+            let team1 = vec![settings.initial_rating(None), settings.initial_rating(None)];
+            let team2 = vec![settings.initial_rating(None), settings.initial_rating(None)];
+
+            let rated = system.rate(&settings, &[(&team1, 1), (&team2, 2)]);
+            let (winners, losers) = (&rated[0], &rated[1]);
+            assert_eq!(winners.len(), 2);
+            assert_eq!(losers.len(), 2);
+            winners
+                .iter()
+                .zip(team1.iter())
+                .for_each(|(new, old)| assert!(new.rating > old.rating, "{:?}", system));
+            losers
+                .iter()
+                .zip(team2.iter())
+                .for_each(|(new, old)| assert!(new.rating < old.rating, "{:?}", system));
+        }
+    }
+
+    #[test]
+    fn test_rate_draw() {
+        for system in SYSTEMS {
+            let settings = Settings::new(system);
+            let stronger = settings.initial_rating(Some(settings.initial_rating * 1.2));
+            let weaker = settings.initial_rating(None);
+
+            let rated = system.rate(&settings, &[(&[stronger], 1), (&[weaker], 1)]);
+            assert!(rated[0][0].rating < stronger.rating, "{:?}", system);
+            assert!(rated[1][0].rating > weaker.rating, "{:?}", system);
+        }
+    }
+
+    #[test]
+    fn test_rate_multi_team() {
+        for system in SYSTEMS {
+            let settings = Settings::new(system);
+            let team = vec![settings.initial_rating(None), settings.initial_rating(None)];
+            let player = vec![settings.initial_rating(None)];
+
+            let rated = system.rate(
+                &settings,
+                &[(&team, 1), (&player, 2), (&team, 2), (&player, 4)],
+            );
+            assert_eq!(rated.len(), 4);
+            assert_eq!(rated[0].len(), 2);
+            assert!(rated[0][0].rating > team[0].rating, "{:?}", system);
+            assert!(rated[1][0].rating > rated[3][0].rating, "{:?}", system);
+            assert!(rated[3][0].rating < player[0].rating, "{:?}", system);
+        }
+    }
+
+    #[test]
+    fn test_rate_pairwise() {
+        let place_of = |i: usize| [1, 2, 2][i];
+        for system in [
+            RatingSystem::TrueSkill,
+            RatingSystem::Glicko2,
+            RatingSystem::Elo,
+        ] {
+            let settings = Settings::new(system);
+            let teams = [
+                vec![settings.initial_rating(Some(settings.initial_rating * 1.1))],
+                vec![settings.initial_rating(None); 2],
+                vec![settings.initial_rating(Some(settings.initial_rating * 0.9))],
+            ];
+            let rated = system.rate(&settings, &[(&teams[0], 1), (&teams[1], 2), (&teams[2], 2)]);
+
+            // Every team's change is the average of its changes in the two team matches
+            for (i, team) in teams.iter().enumerate() {
+                let changes: Vec<f64> = (0..teams.len())
+                    .filter(|j| *j != i)
+                    .map(|j| {
+                        let pair = system
+                            .rate(&settings, &[(team, place_of(i)), (&teams[j], place_of(j))]);
+                        pair[0][0].rating - team[0].rating
+                    })
+                    .collect();
+                let expected = team[0].rating + changes.iter().sum::<f64>() / 2.0;
+                assert!((rated[i][0].rating - expected).abs() < 1e-9, "{:?}", system);
+            }
+        }
+
+        // Weng-Lin uses its own multi team rating
+        let settings = Settings::new(RatingSystem::WengLin);
+        let player = vec![settings.initial_rating(None)];
+        let rated =
+            RatingSystem::WengLin.rate(&settings, &[(&player, 1), (&player, 2), (&player, 3)]);
+        let weng_lin = <WengLin as MultiTeamRatingSystem>::new(weng_lin_config(&settings));
+        let rating = WengLinRating::from(player[0]);
+        let expected = MultiTeamRatingSystem::rate(
+            &weng_lin,
+            &[
+                (&[rating][..], MultiTeamOutcome::new(1)),
+                (&[rating][..], MultiTeamOutcome::new(2)),
+                (&[rating][..], MultiTeamOutcome::new(3)),
+            ],
+        );
+        assert_eq!(rated[1][0], PlayerRating::from(expected[1][0]));
+    }
+
+    #[test]
+    fn test_ranking_score() {
+        let mut settings = Settings::new(RatingSystem::TrueSkill);
+        let veteran = PlayerRating::from((27.0, 1.0));
+        let newcomer = PlayerRating::from((30.0, 6.0));
+
+        assert!(settings.ranking_score(&newcomer) > settings.ranking_score(&veteran));
+
+        settings.ranking_mode = RankingMode::Conservative;
+        assert_eq!(settings.ranking_score(&veteran), 24.0);
+        assert_eq!(settings.ranking_score(&newcomer), 12.0);
+
+        settings.conservative_k = 0.0;
+        assert_eq!(settings.ranking_score(&newcomer), 30.0);
+    }
+
+    #[test]
+    fn test_decayed() {
+        let rating = PlayerRating::from((30.0, 3.0));
+
+        assert_eq!(rating.decayed(0.0, 0.5, 25.0 / 3.0), rating);
+        assert_eq!(rating.decayed(10.0, 0.0, 25.0 / 3.0), rating);
+        assert_eq!(rating.decayed(64.0, 0.5, 25.0 / 3.0).uncertainty, 5.0);
+        assert_eq!(rating.decayed(64.0, 0.5, 25.0 / 3.0).rating, 30.0);
+        assert_eq!(
+            rating.decayed(10000.0, 0.5, 25.0 / 3.0).uncertainty,
+            25.0 / 3.0
+        );
+        // Players already above the maximum keep their uncertainty
+        assert_eq!(rating.decayed(100.0, 0.5, 1.0), rating);
+    }
+
+    #[test]
+    fn test_expected_score() {
+        for system in SYSTEMS {
+            let settings = Settings::new(system);
+            let strong = vec![settings.initial_rating(Some(settings.initial_rating * 1.2)); 2];
+            let weak = vec![settings.initial_rating(None); 2];
+
+            let (strong_win, weak_win) = system.expected_score(&settings, &strong, &weak);
+            assert!(strong_win > 0.5, "{:?}", system);
+            assert!((strong_win + weak_win - 1.0).abs() < 1e-9, "{:?}", system);
+
+            let (even, _) = system.expected_score(&settings, &weak, &weak);
+            assert!((even - 0.5).abs() < 1e-6, "{:?}", system);
+        }
+
+        let settings = Settings::new(RatingSystem::TrueSkill);
+        let even = vec![settings.initial_rating(None); 2];
+        let uneven = vec![settings.initial_rating(Some(40.0)); 2];
+        let even_quality = RatingSystem::TrueSkill.match_quality(&settings, &even, &even);
+        let uneven_quality = RatingSystem::TrueSkill.match_quality(&settings, &uneven, &even);
+        assert!(even_quality.unwrap() > uneven_quality.unwrap());
+        assert_eq!(
+            RatingSystem::Elo.match_quality(&settings, &even, &even),
+            None
+        );
+    }
+
+    #[test]
+    fn test_margin_multiplier() {
+        assert_eq!(margin_multiplier(&[Some(21.0), Some(19.0)], 0.0), 1.0);
+        assert_eq!(margin_multiplier(&[Some(21.0), None], 1.0), 1.0);
+        assert_eq!(margin_multiplier(&[Some(0.0), Some(0.0)], 1.0), 1.0);
+        assert_eq!(margin_multiplier(&[Some(21.0), Some(19.0)], 1.0), 1.05);
+        assert_eq!(margin_multiplier(&[Some(21.0), Some(0.0)], 2.0), 3.0);
+        assert!(
+            margin_multiplier(&[Some(21.0), Some(3.0)], 1.0)
+                > margin_multiplier(&[Some(21.0), Some(19.0)], 1.0)
+        );
+    }
+
+    #[test]
+    fn test_rate_beta() {
+        let team1 = vec![PlayerRating::from((25.0, 25.0 / 3.0))];
+        let team2 = vec![PlayerRating::from((25.0, 25.0 / 3.0))];
+
+        let low_beta = Settings::new(RatingSystem::TrueSkill);
+        let high_beta = Settings {
+            beta: 12.0,
+            ..low_beta
+        };
+
+        let low = RatingSystem::TrueSkill.rate(&low_beta, &[(&team1, 1), (&team2, 2)]);
+        let high = RatingSystem::TrueSkill.rate(&high_beta, &[(&team1, 1), (&team2, 2)]);
+        assert!(low[0][0].rating > high[0][0].rating);
+    }
+
+    #[test]
+    fn test_legacy_rating_deserialize() {
+        let rating: PlayerRating =
+            serde_json::from_str(r#"{"rating":25.0,"uncertainty":8.0}"#).unwrap();
+        assert_eq!(rating, PlayerRating::from((25.0, 8.0)));
+    }
+}
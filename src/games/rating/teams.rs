@@ -0,0 +1,234 @@
+use skillratings::elo::{elo, expected_score as elo_expected_score, EloConfig, EloRating};
+use skillratings::glicko2::{
+    expected_score as glicko2_expected_score, glicko2, Glicko2Config, Glicko2Rating,
+};
+use skillratings::{Outcomes, TeamRatingSystem};
+
+/// Elo for teams. Each team is treated as a single player with the rating from [`team_ratings`]
+/// and every member receives the rating change of their team.
+pub struct EloTeams {
+    config: EloConfig,
+}
+
+impl TeamRatingSystem for EloTeams {
+    type RATING = EloRating;
+    type CONFIG = EloConfig;
+
+    fn new(config: Self::CONFIG) -> Self {
+        EloTeams { config }
+    }
+
+    fn rate(
+        &self,
+        team_one: &[EloRating],
+        team_two: &[EloRating],
+        outcome: &Outcomes,
+    ) -> (Vec<EloRating>, Vec<EloRating>) {
+        let (one, two) = elo_teams(team_one, team_two);
+
+        let (one_final, two_final) = elo(&one, &two, outcome, &self.config);
+        let one_diff = one_final.rating - one.rating;
+        let two_diff = two_final.rating - two.rating;
+
+        (
+            team_one
+                .iter()
+                .map(|player| EloRating::from(player.rating + one_diff))
+                .collect(),
+            team_two
+                .iter()
+                .map(|player| EloRating::from(player.rating + two_diff))
+                .collect(),
+        )
+    }
+
+    fn expected_score(&self, team_one: &[EloRating], team_two: &[EloRating]) -> (f64, f64) {
+        let (one, two) = elo_teams(team_one, team_two);
+        elo_expected_score(&one, &two)
+    }
+}
+
+fn elo_teams(team_one: &[EloRating], team_two: &[EloRating]) -> (EloRating, EloRating) {
+    let ratings =
+        |team: &[EloRating]| -> Vec<f64> { team.iter().map(|player| player.rating).collect() };
+    let (one, two) = team_ratings(&ratings(team_one), &ratings(team_two));
+
+    (EloRating::from(one), EloRating::from(two))
+}
+
+/// Rating each team is compared by. Teams of the same size use the average rating of their
+/// members. An extra player should always make a team stronger, so teams of different sizes use
+/// the rating of a single player as strong as the whole team instead. Elo and Glicko-2 expect a
+/// player to win with the odds of their strengths `10^(rating / 400)`, so that is the rating with
+/// the sum of the members' strengths.
+fn team_ratings(team_one: &[f64], team_two: &[f64]) -> (f64, f64) {
+    if team_one.len() == team_two.len() {
+        return (average(team_one), average(team_two));
+    }
+
+    (combined_rating(team_one), combined_rating(team_two))
+}
+
+fn average(ratings: &[f64]) -> f64 {
+    ratings.iter().sum::<f64>() / ratings.len() as f64
+}
+
+fn combined_rating(ratings: &[f64]) -> f64 {
+    // Strengths are relative to the strongest player so they can't overflow
+    let max = ratings.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let strength: f64 = ratings
+        .iter()
+        .map(|rating| 10f64.powf((rating - max) / 400.0))
+        .sum();
+
+    max + 400.0 * strength.log10()
+}
+
+/// Glicko-2 for teams. Each player is rated against a composite opponent with the average
+/// deviation and volatility of the other team. With teams of the same size the opponent has the
+/// average rating of the other team. Otherwise the opponent is as far from the player as their
+/// team is from the other team by the ratings from [`team_ratings`].
+pub struct Glicko2Teams {
+    config: Glicko2Config,
+}
+
+impl TeamRatingSystem for Glicko2Teams {
+    type RATING = Glicko2Rating;
+    type CONFIG = Glicko2Config;
+
+    fn new(config: Self::CONFIG) -> Self {
+        Glicko2Teams { config }
+    }
+
+    fn rate(
+        &self,
+        team_one: &[Glicko2Rating],
+        team_two: &[Glicko2Rating],
+        outcome: &Outcomes,
+    ) -> (Vec<Glicko2Rating>, Vec<Glicko2Rating>) {
+        let (one, two) = glicko2_teams(team_one, team_two);
+        let same_size = team_one.len() == team_two.len();
+        let opponent = |player: &Glicko2Rating, team: &Glicko2Rating, other: &Glicko2Rating| {
+            if same_size {
+                return *other;
+            }
+
+            Glicko2Rating {
+                rating: player.rating + other.rating - team.rating,
+                ..*other
+            }
+        };
+
+        (
+            team_one
+                .iter()
+                .map(|player| {
+                    glicko2(player, &opponent(player, &one, &two), outcome, &self.config).0
+                })
+                .collect(),
+            team_two
+                .iter()
+                .map(|player| {
+                    glicko2(&opponent(player, &two, &one), player, outcome, &self.config).1
+                })
+                .collect(),
+        )
+    }
+
+    fn expected_score(&self, team_one: &[Glicko2Rating], team_two: &[Glicko2Rating]) -> (f64, f64) {
+        let (one, two) = glicko2_teams(team_one, team_two);
+        glicko2_expected_score(&one, &two)
+    }
+}
+
+fn glicko2_teams(
+    team_one: &[Glicko2Rating],
+    team_two: &[Glicko2Rating],
+) -> (Glicko2Rating, Glicko2Rating) {
+    let ratings =
+        |team: &[Glicko2Rating]| -> Vec<f64> { team.iter().map(|player| player.rating).collect() };
+    let (one, two) = team_ratings(&ratings(team_one), &ratings(team_two));
+
+    (glicko2_team(team_one, one), glicko2_team(team_two, two))
+}
+
+fn glicko2_team(team: &[Glicko2Rating], rating: f64) -> Glicko2Rating {
+    let len = team.len() as f64;
+    let variance: f64 = team.iter().map(|player| player.deviation.powi(2)).sum();
+    let volatility: f64 = team.iter().map(|player| player.volatility).sum();
+
+    Glicko2Rating {
+        rating,
+        deviation: (variance / len).sqrt(),
+        volatility: volatility / len,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_equal_size_teams() {
+        let elo = EloTeams::new(EloConfig::new());
+        let ratings = |ratings: &[f64]| -> Vec<EloRating> {
+            ratings
+                .iter()
+                .map(|rating| EloRating::from(*rating))
+                .collect()
+        };
+
+        // Teams of the same size with the same average are even
+        let (one, two) =
+            elo.expected_score(&ratings(&[1000.0, 1000.0]), &ratings(&[1200.0, 800.0]));
+        assert!((one - 0.5).abs() < 1e-9);
+        assert!((two - 0.5).abs() < 1e-9);
+        let (one, _) = elo.expected_score(&ratings(&[1100.0, 900.0]), &ratings(&[1000.0, 1000.0]));
+        assert!((one - 0.5).abs() < 1e-9);
+
+        let glicko2_teams = Glicko2Teams::new(Glicko2Config::new());
+        let rating = |rating: f64| Glicko2Rating {
+            rating,
+            ..Glicko2Rating::new()
+        };
+        let one = [rating(1500.0), rating(1500.0)];
+        let two = [rating(1700.0), rating(1300.0)];
+        let (expected, _) = glicko2_teams.expected_score(&one, &two);
+        assert!((expected - 0.5).abs() < 1e-9);
+
+        // Each player plays the average of the other team
+        let (new_one, new_two) = glicko2_teams.rate(&one, &two, &Outcomes::WIN);
+        let composite = glicko2_team(&two, 1500.0);
+        let config = Glicko2Config::new();
+        assert_eq!(
+            new_one[0],
+            glicko2(&one[0], &composite, &Outcomes::WIN, &config).0
+        );
+        assert_eq!(
+            new_two[1],
+            glicko2(
+                &glicko2_team(&one, 1500.0),
+                &two[1],
+                &Outcomes::WIN,
+                &config
+            )
+            .1
+        );
+    }
+
+    #[test]
+    fn test_uneven_teams() {
+        let elo = EloTeams::new(EloConfig::new());
+        let team = |players: usize| vec![EloRating::from(1000.0); players];
+
+        // An extra player of the same rating makes a team twice as strong
+        let (two, one) = elo.expected_score(&team(2), &team(1));
+        assert!((two - 2.0 / 3.0).abs() < 1e-9);
+        assert!((one - 1.0 / 3.0).abs() < 1e-9);
+
+        let glicko2_teams = Glicko2Teams::new(Glicko2Config::new());
+        let team = |players: usize| vec![Glicko2Rating::new(); players];
+        let (three, two) = glicko2_teams.expected_score(&team(3), &team(2));
+        assert!(three > two);
+    }
+}
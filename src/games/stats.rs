@@ -0,0 +1,277 @@
+use crate::rankings::Match;
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+use skillratings::Outcomes;
+
+pub fn result_name(outcome: Outcomes) -> &'static str {
+    match outcome {
+        Outcomes::WIN => "Win",
+        Outcomes::DRAW => "Draw",
+        Outcomes::LOSS => "Loss",
+    }
+}
+
+/// Record of a player with or against another player
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Matchup {
+    pub(crate) player: u16,
+    pub(crate) games: u16,
+    pub(crate) wins: u16,
+    pub(crate) draws: u16,
+    pub(crate) losses: u16,
+}
+
+impl Matchup {
+    fn add(&mut self, outcome: Outcomes) {
+        self.games += 1;
+        match outcome {
+            Outcomes::WIN => self.wins += 1,
+            Outcomes::DRAW => self.draws += 1,
+            Outcomes::LOSS => self.losses += 1,
+        }
+    }
+
+    /// Draws count as half a win
+    pub fn win_rate(&self) -> f64 {
+        match self.games {
+            0 => 0.0,
+            games => (self.wins as f64 + self.draws as f64 / 2.0) / games as f64,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PlayerStats {
+    pub(crate) record: Matchup, // Record over every match, `player` is the player themselves
+    pub(crate) streak: u16,     // Matches in a row with the same result as the latest match
+    pub(crate) streak_result: Option<&'static str>,
+    pub(crate) longest_win_streak: u16,
+    pub(crate) longest_loss_streak: u16,
+    pub(crate) teammates: Vec<Matchup>, // Most games together first
+    pub(crate) opponents: Vec<Matchup>, // Most games against first
+    pub(crate) best_matchup: Option<Matchup>,
+    pub(crate) worst_matchup: Option<Matchup>,
+}
+
+fn sorted(matchups: HashMap<u16, Matchup>) -> Vec<Matchup> {
+    let mut matchups: Vec<Matchup> = matchups.into_values().collect();
+    matchups.sort_by(|a, b| b.games.cmp(&a.games).then(a.player.cmp(&b.player)));
+    matchups
+}
+
+/// Time each player last played a match, in milliseconds since epoch. Matches recorded before
+/// timestamps are skipped, so players with only those matches have no time.
+pub fn last_played(matches: &[Match]) -> HashMap<u16, u64> {
+    let mut last_played: HashMap<u16, u64> = HashMap::new();
+    matches.iter().filter(|m| m.played_at != 0).for_each(|m| {
+        m.players().into_iter().for_each(|player| {
+            let time = last_played.entry(player).or_insert(m.played_at);
+            *time = (*time).max(m.played_at);
+        })
+    });
+    last_played
+}
+
+/// Stats of the player over the provided matches, which should be ordered oldest first
+pub fn player_stats(matches: &[Match], player: u16) -> PlayerStats {
+    let mut record = Matchup {
+        player,
+        ..Default::default()
+    };
+    let mut teammates: HashMap<u16, Matchup> = HashMap::new();
+    let mut opponents: HashMap<u16, Matchup> = HashMap::new();
+    let mut outcomes = vec![];
+
+    matches.iter().for_each(|m| {
+        let team = match m.teams.iter().find(|team| team.players.contains(&player)) {
+            Some(team) => team,
+            None => return,
+        };
+        let outcome = m.outcome(team);
+        record.add(outcome);
+        outcomes.push(outcome);
+
+        m.teams.iter().for_each(|other| {
+            let matchups = if other == team {
+                &mut teammates
+            } else {
+                &mut opponents
+            };
+            other
+                .players
+                .iter()
+                .filter(|other_player| **other_player != player)
+                .for_each(|other_player| {
+                    matchups
+                        .entry(*other_player)
+                        .or_insert(Matchup {
+                            player: *other_player,
+                            ..Default::default()
+                        })
+                        .add(outcome)
+                });
+        });
+    });
+
+    let streak_result = outcomes.last().copied();
+    let streak = outcomes
+        .iter()
+        .rev()
+        .take_while(|outcome| Some(**outcome) == streak_result)
+        .count() as u16;
+    let longest = |result: Outcomes| {
+        outcomes
+            .split(|outcome| *outcome != result)
+            .map(|run| run.len() as u16)
+            .max()
+            .unwrap_or(0)
+    };
+
+    let opponents = sorted(opponents);
+    // Ties in win rate go to the opponent with more games
+    let best_matchup = opponents
+        .iter()
+        .max_by(|a, b| {
+            a.win_rate()
+                .total_cmp(&b.win_rate())
+                .then(a.games.cmp(&b.games))
+        })
+        .copied();
+    // Only an opponent with a lower win rate than the best matchup is a worse matchup
+    let worst_matchup = opponents
+        .iter()
+        .min_by(|a, b| {
+            a.win_rate()
+                .total_cmp(&b.win_rate())
+                .then(b.games.cmp(&a.games))
+        })
+        .filter(|worst| best_matchup.is_some_and(|best| worst.win_rate() < best.win_rate()))
+        .copied();
+
+    PlayerStats {
+        record,
+        streak,
+        streak_result: streak_result.map(result_name),
+        longest_win_streak: longest(Outcomes::WIN),
+        longest_loss_streak: longest(Outcomes::LOSS),
+        teammates: sorted(teammates),
+        opponents,
+        best_matchup,
+        worst_matchup,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::games::fixtures::two_team_match;
+    use crate::games::rating::RatingSystem;
+    use crate::rankings::Settings;
+
+    #[test]
+    fn test_player_stats() {
+        let matches = vec![
+            two_team_match(0, vec![0, 1], vec![2, 3]),
+            two_team_match(1, vec![0, 1], vec![2, 3]),
+            two_team_match(2, vec![2, 0], vec![1, 3]),
+            two_team_match(3, vec![1, 3], vec![0, 2]),
+            two_team_match(4, vec![1, 2], vec![0, 3]),
+            two_team_match(5, vec![1, 2], vec![3]),
+        ];
+        let stats = player_stats(&matches, 0);
+
+        assert_eq!(stats.record.games, 5);
+        assert_eq!(stats.record.wins, 3);
+        assert_eq!(stats.record.losses, 2);
+        assert_eq!(stats.record.win_rate(), 0.6);
+        assert_eq!(stats.streak, 2);
+        assert_eq!(stats.streak_result, Some("Loss"));
+        assert_eq!(stats.longest_win_streak, 3);
+        assert_eq!(stats.longest_loss_streak, 2);
+
+        assert_eq!(stats.teammates[0].player, 1);
+        assert_eq!(stats.teammates[0].games, 2);
+        assert_eq!(stats.teammates[0].wins, 2);
+        assert_eq!(stats.opponents[0].player, 3);
+        assert_eq!(stats.opponents[0].games, 4);
+
+        // 3 of 4 against player 3 and 1 of 3 against player 1
+        assert_eq!(stats.best_matchup.unwrap().player, 3);
+        assert_eq!(stats.worst_matchup.unwrap().player, 1);
+    }
+
+    #[test]
+    fn test_last_played() {
+        let mut matches = vec![
+            two_team_match(0, vec![0], vec![1]),
+            two_team_match(1, vec![1], vec![2]),
+            two_team_match(2, vec![0], vec![2]),
+            two_team_match(3, vec![4], vec![1]),
+        ];
+        matches[0].played_at = 300;
+        matches[1].played_at = 100;
+        matches[2].played_at = 200;
+
+        let last_played = last_played(&matches);
+        assert_eq!(last_played[&0], 300);
+        assert_eq!(last_played[&1], 300);
+        assert_eq!(last_played[&2], 200);
+        assert_eq!(last_played.get(&3), None);
+
+        // Player 4 only has a match from before timestamps so isn't decayed or inactive
+        assert_eq!(last_played.get(&4), None);
+        let settings = Settings {
+            uncertainty_decay: 0.5,
+            inactive_days: Some(30),
+            ..Settings::new(RatingSystem::TrueSkill)
+        };
+        let rating = settings.initial_rating(None);
+        let now = 1_000_000_000_000;
+        assert_eq!(
+            settings.decayed_rating(&rating, last_played.get(&4).copied(), now),
+            rating
+        );
+        assert!(!settings.is_inactive(last_played.get(&4).copied(), now));
+    }
+
+    #[test]
+    fn test_player_stats_draw_streak() {
+        let mut draw = two_team_match(1, vec![0], vec![1]);
+        draw.teams[1].place = 1;
+        let matches = vec![two_team_match(0, vec![0], vec![1]), draw.clone(), draw];
+        let stats = player_stats(&matches, 1);
+
+        assert_eq!(stats.streak, 2);
+        assert_eq!(stats.streak_result, Some("Draw"));
+        assert_eq!(stats.record.win_rate(), 1.0 / 3.0);
+        assert!(stats.teammates.is_empty());
+
+        let stats = player_stats(&matches, 5);
+        assert_eq!(stats.record.games, 0);
+        assert_eq!(stats.streak_result, None);
+        assert_eq!(stats.best_matchup, None);
+    }
+
+    #[test]
+    fn test_matchups_without_worse_opponent() {
+        let matches = vec![
+            two_team_match(0, vec![0], vec![1]),
+            two_team_match(1, vec![1], vec![0]),
+        ];
+        let stats = player_stats(&matches, 0);
+        assert_eq!(stats.best_matchup.unwrap().player, 1);
+        assert_eq!(stats.worst_matchup, None);
+
+        let matches = vec![
+            two_team_match(0, vec![0], vec![1]),
+            two_team_match(1, vec![2], vec![0]),
+            two_team_match(2, vec![0], vec![2]),
+            two_team_match(3, vec![1], vec![0]),
+        ];
+        let stats = player_stats(&matches, 0);
+        assert_eq!(stats.best_matchup.unwrap().win_rate(), 0.5);
+        assert_eq!(stats.worst_matchup, None);
+    }
+}
@@ -4,22 +4,88 @@ mod scripts;
 mod utils;
 
 use games::matchmaking;
-use rankings::{Empty, Match, Player, Session};
+use games::rating::{RankingMode, RatingSystem};
+use games::stats::{self, Matchup};
+use games::Prediction;
+use rankings::{BoardCreate, Empty, Match, MatchRecord, Player, Session, Settings};
 
 use futures::try_join;
+use rand::SeedableRng;
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
-use skillratings::trueskill::{TrueSkill, TrueSkillConfig, TrueSkillRating};
-use skillratings::{Rating, TeamRatingSystem};
+use skillratings::Rating;
 use tinytemplate::TinyTemplate;
 use worker::*;
 
-use crate::games::matchmaking::GameInfo;
-use crate::utils::format_float;
+use crate::games::matchmaking::{GameInfo, MatchRng, MatchmakingError, Strategy};
+use crate::utils::{format_float, format_percent};
 
 // Should probably use type parameter for structs where types are used
-type RatingType = TrueSkillRating;
+type RatingType = games::rating::PlayerRating;
+
+#[derive(Serialize)]
+struct TeamString {
+    place: usize,
+    score: Option<String>,
+    players: Vec<String>,
+}
+
+fn team_strings(m: &Match, players: &HashMap<u16, Player<RatingType>>) -> Vec<TeamString> {
+    m.teams
+        .iter()
+        .map(|team| TeamString {
+            place: team.place,
+            score: team.score.map(|score| score.to_string()),
+            players: team
+                .players
+                .iter()
+                .map(|player| players.get(player).unwrap().name.clone())
+                .collect(),
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct GamePlayerString {
+    id: u16,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct GameTeamString {
+    players: Vec<GamePlayerString>,
+    rating: f64,
+}
+
+#[derive(Serialize)]
+struct GameString {
+    id: u16,
+    teams: Vec<GameTeamString>,
+    prediction: Prediction,
+}
+
+#[derive(Serialize)]
+struct GamesString {
+    games: Vec<GameString>,
+    seed: u64,
+    strategy: Strategy, // Strategy the games were generated with, which may differ from the chosen one
+}
+
+/// Generated games are sent as json unless html is asked for in the Accept header
+fn accepts_html(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| accept.contains("text/html"))
+}
+
+fn render_games(games: &GamesString) -> Result<String> {
+    let mut tt = TinyTemplate::new();
+    tt.add_template("/games", include_str!("../content/games.html"))
+        .map_err(|err| err.to_string())?;
+    tt.add_formatter("format_float", format_float);
+    tt.add_formatter("format_percent", format_percent);
+
+    Ok(tt.render("/games", games).map_err(|err| err.to_string())?)
+}
 
 #[event(fetch)]
 pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
@@ -60,6 +126,50 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
             let stub = namespace.id_from_name(id)?.get_stub()?;
             stub.fetch_with_request(req).await
         })
+        .on_async("/:id/settings", |req, ctx| async move {
+            let id = ctx.param("id").unwrap();
+            let namespace = ctx.durable_object("RANKINGS")?;
+
+            let client = rankings::Client::new(&ctx, id)?;
+            if !client.check_pass(&req).await? {
+                return Response::error("", 401);
+            }
+            let stub = namespace.id_from_name(id)?.get_stub()?;
+            stub.fetch_with_request(req).await
+        })
+        .on_async("/:id/pending", |req, ctx| async move {
+            let id = ctx.param("id").unwrap();
+            let namespace = ctx.durable_object("RANKINGS")?;
+
+            let client = rankings::Client::new(&ctx, id)?;
+            if !client.check_pass(&req).await? {
+                return Response::error("", 401);
+            }
+            let stub = namespace.id_from_name(id)?.get_stub()?;
+            stub.fetch_with_request(req).await
+        })
+        .on_async("/:id/merge-players", |req, ctx| async move {
+            let id = ctx.param("id").unwrap();
+            let namespace = ctx.durable_object("RANKINGS")?;
+
+            let client = rankings::Client::new(&ctx, id)?;
+            if !client.check_pass(&req).await? {
+                return Response::error("", 401);
+            }
+            let stub = namespace.id_from_name(id)?.get_stub()?;
+            stub.fetch_with_request(req).await
+        })
+        .on_async("/:id/recompute", |req, ctx| async move {
+            let id = ctx.param("id").unwrap();
+            let namespace = ctx.durable_object("RANKINGS")?;
+
+            let client = rankings::Client::new(&ctx, id)?;
+            if !client.check_pass(&req).await? {
+                return Response::error("", 401);
+            }
+            let stub = namespace.id_from_name(id)?.get_stub()?;
+            stub.fetch_with_request(req).await
+        })
         .on_async("/create/:id", |mut req, ctx| async move {
             let id = ctx.param("id").unwrap();
             let client = rankings::Client::new(&ctx, id)?;
@@ -69,8 +179,8 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 return Response::error("ID already exists", 406);
             }
 
-            let pass: String = req.text().await?;
-            let _: Empty = client.fetch("/setup", &pass, Method::Put).await?;
+            let body = BoardCreate::from_body(req.text().await?);
+            let _: Empty = client.fetch("/setup", &body, Method::Put).await?;
 
             ctx.kv("SKILLRANK_IDS")?
                 .put(id, Date::now().to_string())?
@@ -81,59 +191,336 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
         .on_async("/:id/generate-matches", |mut req, ctx| async move {
             let id = ctx.param("id").unwrap();
             let client = rankings::Client::new(&ctx, id)?;
+            if !client.check_pass(&req).await? {
+                return Response::error("", 401);
+            }
+            let html = accepts_html(req.headers().get("Accept")?.as_deref());
             let body_fut = req.json();
             let players_fut = client.fetch("/players", "", Method::Get);
             let session_fut = client.fetch("/session", "", Method::Get);
+            let rating_system_fut = client.fetch("/rating-system", "", Method::Get);
+            let settings_fut = client.fetch("/settings", "", Method::Get);
 
+            /// `preview` generates games without keeping them as pending games, so a seed can be
+            /// used to regenerate games which are already pending
             #[derive(Deserialize)]
             struct MatchInfo {
                 participants: Vec<u16>,
                 game_info: GameInfo,
+                #[serde(default)]
+                preview: bool,
             }
 
-            let info: (MatchInfo, HashMap<u16, Player<RatingType>>, Option<Session>) =
-                try_join!(body_fut, players_fut, session_fut)?;
-            let (body, players, session) = info;
-
-            let sesh = session.unwrap();
-
-            let matches = matchmaking::generate_matches(body.participants, &players, sesh, body.game_info)?;
-            let matches_str: String = matches.iter().fold("".to_string(), |acc, m| {
-                let team_1 = m.team1.iter().fold("".to_string(), |team1_acc, p| {
-                    let player = players.get(p).unwrap();
-                    format!("{}{}, ", team1_acc, player.name)
-                });
-                let team_2 = m.team2.iter().fold("".to_string(), |team2_acc, p| {
-                    let player = players.get(p).unwrap();
-                    format!("{}{}, ", team2_acc, player.name)
-                });
-
-                format!(
-                    "{}Game {}<br>Team 1:{}<br>Team 2:{}<br><br>",
-                    acc,
-                    m.id + 1,
-                    team_1,
-                    team_2
-                )
-            });
-            Response::ok(matches_str)
+            let info: (
+                MatchInfo,
+                HashMap<u16, Player<RatingType>>,
+                Option<Session>,
+                RatingSystem,
+                Settings,
+            ) = try_join!(
+                body_fut,
+                players_fut,
+                session_fut,
+                rating_system_fut,
+                settings_fut
+            )?;
+            let (body, players, session, rating_system, settings) = info;
+
+            let sesh = match session {
+                Some(sesh) => sesh,
+                None => {
+                    return Ok(Response::from_json(&MatchmakingError::NoSession)?.with_status(400))
+                }
+            };
+
+            // Players still in a pending game can't be put in another one. When previewing a seed
+            // only the games which were already pending when it was used are left out.
+            let pending_players = match (body.preview, body.game_info.seed) {
+                (true, Some(seed)) => sesh.pending_players_before(seed),
+                _ => sesh.pending_players(),
+            };
+            let participants: Vec<u16> = body
+                .participants
+                .into_iter()
+                .filter(|player| !pending_players.contains(player))
+                .collect();
+
+            let seed = body.game_info.seed()?;
+            let strategy = body.game_info.strategy_used(&participants);
+            let matches = matchmaking::generate_matches(
+                participants,
+                &players,
+                sesh,
+                body.game_info,
+                rating_system,
+                &settings,
+                &mut MatchRng::seed_from_u64(seed),
+            );
+            let mut matches = match matches {
+                Ok(matches) => matches,
+                Err(err) => return Ok(Response::from_json(&err)?.with_status(400)),
+            };
+            matches.iter_mut().for_each(|game| game.seed = Some(seed));
+            if !body.preview {
+                matches = client.fetch("/pending", &matches, Method::Post).await?;
+            }
+
+            let team_string = |team: &[u16]| GameTeamString {
+                players: team
+                    .iter()
+                    .map(|id| GamePlayerString {
+                        id: *id,
+                        name: players.get(id).unwrap().name.clone(),
+                    })
+                    .collect(),
+                rating: team
+                    .iter()
+                    .map(|id| players.get(id).unwrap().rating.rating())
+                    .sum(),
+            };
+            let games = GamesString {
+                games: matches
+                    .iter()
+                    .map(|m| GameString {
+                        id: m.id,
+                        teams: vec![team_string(&m.team1), team_string(&m.team2)],
+                        prediction: games::predict(
+                            &players,
+                            &m.team1,
+                            &m.team2,
+                            rating_system,
+                            &settings,
+                        ),
+                    })
+                    .collect(),
+                seed,
+                strategy,
+            };
+
+            if !html {
+                return Response::from_json(&games);
+            }
+            Response::from_html(render_games(&games)?)
         })
-        .on_async("/:id/add-match", |mut req, ctx| async move {
+        .post_async("/:id/predict", |mut req, ctx| async move {
             let id = ctx.param("id").unwrap();
+            let client = rankings::Client::new(&ctx, id)?;
+            let body_fut = req.json();
+            let players_fut = client.fetch("/players", "", Method::Get);
+            let rating_system_fut = client.fetch("/rating-system", "", Method::Get);
+            let settings_fut = client.fetch("/settings", "", Method::Get);
+
+            #[derive(Deserialize)]
+            struct PredictInfo {
+                team1: Vec<u16>,
+                team2: Vec<u16>,
+            }
+
+            let info: (
+                PredictInfo,
+                HashMap<u16, Player<RatingType>>,
+                RatingSystem,
+                Settings,
+            ) = try_join!(body_fut, players_fut, rating_system_fut, settings_fut)?;
+            let (body, players, rating_system, settings) = info;
+
+            let m: Match = MatchRecord::TwoTeams {
+                id: 0,
+                team1: body.team1,
+                team2: body.team2,
+                outcome: Default::default(),
+            }
+            .into();
+            if let Err(err) = m.validate(&players, None, false) {
+                return Ok(Response::from_json(&err)?.with_status(400));
+            }
+
+            let prediction = games::predict(
+                &players,
+                &m.teams[0].players,
+                &m.teams[1].players,
+                rating_system,
+                &settings,
+            );
+            Response::from_json(&prediction)
+        })
+        .on_async("/:id/add-match", |req, ctx| async move {
+            let id = ctx.param("id").unwrap();
+            let namespace = ctx.durable_object("RANKINGS")?;
+
             let client = rankings::Client::new(&ctx, id)?;
             if !client.check_pass(&req).await? {
                 return Response::error("", 401);
             }
+            let stub = namespace.id_from_name(id)?.get_stub()?;
+            stub.fetch_with_request(req).await
+        })
+        .get_async("/:id/players/:pid/history", |_req, ctx| async move {
+            let id = ctx.param("id").unwrap();
+            let pid: u16 = match ctx.param("pid").unwrap().parse() {
+                Ok(pid) => pid,
+                Err(_) => return Response::error("Invalid player id", 400),
+            };
+            let client = rankings::Client::new(&ctx, id)?;
+            let players_fut = client.fetch("/players", "", Method::Get);
+            let matches_fut = client.fetch("/matches", "", Method::Get);
+            let rating_system_fut = client.fetch("/rating-system", "", Method::Get);
+            let settings_fut = client.fetch("/settings", "", Method::Get);
+
+            let info: (
+                HashMap<u16, Player<RatingType>>,
+                Vec<Match>,
+                RatingSystem,
+                Settings,
+            ) = try_join!(players_fut, matches_fut, rating_system_fut, settings_fut)?;
+            let (players, matches, rating_system, settings) = info;
+
+            if !players.contains_key(&pid) {
+                return Response::error("Player not found", 404);
+            }
+            Response::from_json(&games::rating_history(
+                &players,
+                &matches,
+                rating_system,
+                &settings,
+                pid,
+            ))
+        })
+        .get_async("/:id/player/:pid", |_req, ctx| async move {
+            let template = include_str!("../content/profile.html");
+            let mut tt = TinyTemplate::new();
+            tt.add_template("/profile", template)
+                .map_err(|err| err.to_string())?;
+            tt.add_formatter("format_float", format_float);
 
-            let m: Match = req.json().await?;
-            let rating_system = TrueSkill::new(TrueSkillConfig {
-                draw_probability: 0.0,
-                beta: 6.0,
-                default_dynamics: 0.13,
-            });
+            let id = ctx.param("id").unwrap();
+            let pid: u16 = match ctx.param("pid").unwrap().parse() {
+                Ok(pid) => pid,
+                Err(_) => return Response::error("Invalid player id", 400),
+            };
+            let client = rankings::Client::new(&ctx, id)?;
+            let players_fut = client.fetch("/players", "", Method::Get);
+            let matches_fut = client.fetch("/matches", "", Method::Get);
+            let rating_system_fut = client.fetch("/rating-system", "", Method::Get);
+            let settings_fut = client.fetch("/settings", "", Method::Get);
+
+            let info: (
+                HashMap<u16, Player<RatingType>>,
+                Vec<Match>,
+                RatingSystem,
+                Settings,
+            ) = try_join!(players_fut, matches_fut, rating_system_fut, settings_fut)?;
+            let (players, matches, rating_system, settings) = info;
+
+            let player = match players.get(&pid) {
+                Some(player) => player,
+                None => return Response::error("Player not found", 404),
+            };
 
-            games::add_match(&m.team1, &m.team2, client, rating_system).await?;
-            Response::ok("")
+            #[derive(Serialize)]
+            struct MatchString {
+                id: u16,
+                teams: Vec<TeamString>,
+                played_at: u64,
+                result: &'static str,
+                change: Option<String>,
+            }
+
+            #[derive(Serialize)]
+            struct MatchupString {
+                name: String,
+                games: u16,
+                wins: u16,
+                draws: u16,
+                losses: u16,
+                win_rate: String,
+            }
+
+            #[derive(Serialize)]
+            struct Context {
+                id: String,
+                pid: u16,
+                name: String,
+                archived: bool,
+                rating_system: &'static str,
+                rating: f64,
+                uncertainty: f64,
+                wins: u16,
+                draws: u16,
+                losses: u16,
+                win_rate: String,
+                streak: u16,
+                streak_result: Option<&'static str>,
+                longest_win_streak: u16,
+                longest_loss_streak: u16,
+                teammates: Vec<MatchupString>,
+                opponents: Vec<MatchupString>,
+                best_matchup: Option<MatchupString>,
+                worst_matchup: Option<MatchupString>,
+                matches: Vec<MatchString>,
+            }
+
+            let matchup_string = |matchup: &Matchup| MatchupString {
+                name: players.get(&matchup.player).unwrap().name.clone(),
+                games: matchup.games,
+                wins: matchup.wins,
+                draws: matchup.draws,
+                losses: matchup.losses,
+                win_rate: format!("{:.0}%", matchup.win_rate() * 100.0),
+            };
+            let stats = stats::player_stats(&matches, pid);
+            let history = games::rating_history(&players, &matches, rating_system, &settings, pid);
+
+            let matches_string = matches
+                .iter()
+                .rev()
+                .filter_map(|m| {
+                    let team = m.teams.iter().find(|team| team.players.contains(&pid))?;
+                    let result = stats::result_name(m.outcome(team));
+                    let change = history
+                        .iter()
+                        .find(|entry| entry.match_id == m.id)
+                        .map(|entry| format!("{:+.2}", entry.after.rating - entry.before.rating));
+
+                    Some(MatchString {
+                        id: m.id,
+                        teams: team_strings(m, &players),
+                        played_at: m.played_at,
+                        result,
+                        change,
+                    })
+                })
+                .take(15)
+                .collect();
+
+            let context = Context {
+                id: id.clone(),
+                pid,
+                name: player.name.clone(),
+                archived: player.archived,
+                rating_system: rating_system.name(),
+                rating: player.rating.rating,
+                uncertainty: player.rating.uncertainty,
+                wins: player.wins,
+                draws: player.draws,
+                losses: player.losses,
+                win_rate: format!("{:.0}%", stats.record.win_rate() * 100.0),
+                streak: stats.streak,
+                streak_result: stats.streak_result,
+                longest_win_streak: stats.longest_win_streak,
+                longest_loss_streak: stats.longest_loss_streak,
+                teammates: stats.teammates.iter().take(5).map(matchup_string).collect(),
+                opponents: stats.opponents.iter().take(5).map(matchup_string).collect(),
+                best_matchup: stats.best_matchup.as_ref().map(matchup_string),
+                worst_matchup: stats.worst_matchup.as_ref().map(matchup_string),
+                matches: matches_string,
+            };
+
+            let mut rendered = tt
+                .render("/profile", &context)
+                .map_err(|err| err.to_string())?;
+            rendered.push_str(scripts::PROFILE);
+            Response::from_html(rendered)
         })
         .get_async("/:id/player", |_req, ctx| async move {
             let template = include_str!("../content/player.html");
@@ -142,16 +529,42 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 .map_err(|err| err.to_string())?;
 
             let id = ctx.param("id").unwrap();
+            let client = rankings::Client::new(&ctx, id)?;
+            let settings_fut = client.fetch("/settings", "", Method::Get);
+            let players_fut = client.fetch("/players", "", Method::Get);
+
+            let info: (Settings, HashMap<u16, Player<RatingType>>) =
+                try_join!(settings_fut, players_fut)?;
+            let (settings, players) = info;
+
+            #[derive(Serialize)]
+            struct PlayerString {
+                id: u16,
+                name: String,
+                archived: bool,
+            }
 
             #[derive(Serialize)]
             struct Context {
                 id: String,
                 default_score: f64,
+                players: Vec<PlayerString>,
             }
 
+            let mut players_string: Vec<PlayerString> = players
+                .into_iter()
+                .map(|(id, player)| PlayerString {
+                    id,
+                    name: player.name,
+                    archived: player.archived,
+                })
+                .collect();
+            players_string.sort_by(|a, b| a.name.cmp(&b.name));
+
             let context = Context {
                 id: id.clone(),
-                default_score: 25.0,
+                default_score: settings.initial_rating,
+                players: players_string,
             };
 
             let mut rendered = tt
@@ -167,15 +580,22 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
             let mut tt = TinyTemplate::new();
             tt.add_template("/session", template)
                 .map_err(|err| err.to_string())?;
+            tt.add_formatter("format_percent", format_percent);
 
             let id = ctx.param("id").unwrap();
 
             let players_fut = client.fetch("/players", "", Method::Get);
             let session_fut = client.fetch("/session", "", Method::Get);
+            let settings_fut = client.fetch("/settings", "", Method::Get);
+            let rating_system_fut = client.fetch("/rating-system", "", Method::Get);
 
-            let info: (HashMap<u16, Player<RatingType>>, Option<Session>) =
-                try_join!(players_fut, session_fut)?;
-            let (players, session) = info;
+            let info: (
+                HashMap<u16, Player<RatingType>>,
+                Option<Session>,
+                Settings,
+                RatingSystem,
+            ) = try_join!(players_fut, session_fut, settings_fut, rating_system_fut)?;
+            let (players, session, settings, rating_system) = info;
 
             #[derive(Serialize)]
             struct PlayerString {
@@ -189,11 +609,24 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 session: bool,
                 players: Vec<PlayerString>,
                 session_players: Vec<PlayerString>,
+                pending: Vec<PendingString>,
+                rating_system: RatingSystem,
+                settings: Settings,
+            }
+
+            #[derive(Serialize)]
+            struct PendingString {
+                id: u16,
+                team1: Vec<String>,
+                team2: Vec<String>,
+                prediction: Prediction,
+                seed: Option<u64>,
             }
 
             let players_string = players
                 .clone()
                 .into_iter()
+                .filter(|(_, player)| !player.archived)
                 .map(|(id, player)| PlayerString {
                     name: player.name,
                     id,
@@ -212,11 +645,40 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 None => vec![],
             };
 
+            let names = |team: &[u16]| -> Vec<String> {
+                team.iter()
+                    .map(|id| players.get(id).unwrap().name.clone())
+                    .collect()
+            };
+            let pending = match session {
+                Some(ref s) => s
+                    .pending
+                    .iter()
+                    .map(|game| PendingString {
+                        id: game.id,
+                        team1: names(&game.team1),
+                        team2: names(&game.team2),
+                        prediction: games::predict(
+                            &players,
+                            &game.team1,
+                            &game.team2,
+                            rating_system,
+                            &settings,
+                        ),
+                        seed: game.seed,
+                    })
+                    .collect(),
+                None => vec![],
+            };
+
             let context = Context {
                 id: id.clone(),
                 session: session.is_some(),
                 players: players_string,
                 session_players,
+                pending,
+                rating_system,
+                settings,
             };
 
             let mut rendered = tt
@@ -236,28 +698,47 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
 
             #[derive(Serialize)]
             struct MatchString {
-                winners: Vec<String>,
-                losers: Vec<String>,
+                id: u16,
+                teams: Vec<TeamString>,
+                draw: bool,
+                played_at: u64,
+                note: Option<String>,
+                session: Option<u16>,
             }
 
             #[derive(Serialize)]
             struct PlayerString {
                 rank: usize,
+                id: u16,
                 name: String,
                 score: f64,
+                uncertainty: f64,
+                conservative: f64,
                 wins: u16,
+                draws: u16,
                 losses: u16,
             }
 
             #[derive(Serialize)]
             struct Context {
                 id: String,
+                rating_system: &'static str,
+                conservative: bool,
+                conservative_k: f64,
+                min_games: u16,
+                max_uncertainty: Option<f64>,
                 matches: Vec<MatchString>,
                 players: Vec<PlayerString>,
+                provisional: Vec<PlayerString>,
+                inactive_days: Option<u16>,
+                inactive: Vec<PlayerString>,
             }
 
             let p = client.fetch("/players", "", Method::Get).await;
             let matches: Vec<Match> = client.fetch("/matches", "", Method::Get).await?;
+            let rating_system: RatingSystem =
+                client.fetch("/rating-system", "", Method::Get).await?;
+            let settings: Settings = client.fetch("/settings", "", Method::Get).await?;
 
             let players: HashMap<u16, Player<RatingType>> = match p {
                 Ok(players) => players,
@@ -274,38 +755,75 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 .rev()
                 .take(15)
                 .map(|m| MatchString {
-                    winners: m
-                        .team1
-                        .iter()
-                        .map(|winner| players.get(winner).unwrap().name.clone())
-                        .collect(),
-                    losers: m
-                        .team2
-                        .iter()
-                        .map(|loser| players.get(loser).unwrap().name.clone())
-                        .collect(),
+                    id: m.id,
+                    teams: team_strings(m, &players),
+                    draw: m.is_draw(),
+                    played_at: m.played_at,
+                    note: m.note.clone(),
+                    session: m.session.map(|session| session + 1),
                 })
                 .collect();
 
-            let mut players_vec: Vec<&Player<RatingType>> = players.values().collect();
-            players_vec
-                .sort_by(|a, b| (b.rating.rating() as isize).cmp(&(a.rating.rating() as isize)));
-            let players_string = players_vec
+            // Inactivity is applied when the leaderboard is shown so stored ratings are unchanged
+            let now = Date::now().as_millis();
+            let last_played = stats::last_played(&matches);
+            let mut players_vec: Vec<(u16, Player<RatingType>)> = players
+                .into_iter()
+                .filter(|(_, player)| !player.archived)
+                .map(|(id, mut player)| {
+                    let last = last_played.get(&id).copied();
+                    player.rating = settings.decayed_rating(&player.rating, last, now);
+                    (id, player)
+                })
+                .collect();
+            players_vec.sort_by(|(_, a), (_, b)| {
+                settings
+                    .ranking_score(&b.rating)
+                    .total_cmp(&settings.ranking_score(&a.rating))
+            });
+            let (inactive_vec, active_vec): (Vec<_>, Vec<_>) = players_vec
+                .into_iter()
+                .partition(|(id, _)| settings.is_inactive(last_played.get(id).copied(), now));
+            let (provisional_vec, ranked_vec): (Vec<_>, Vec<_>) = active_vec
+                .into_iter()
+                .partition(|(_, player)| settings.is_provisional(player));
+            let player_string = |rank: usize, id: u16, player: &Player<RatingType>| PlayerString {
+                rank,
+                id,
+                name: player.name.clone(),
+                score: player.rating.rating(),
+                uncertainty: player.rating.uncertainty,
+                conservative: player.rating.conservative(settings.conservative_k),
+                wins: player.wins,
+                draws: player.draws,
+                losses: player.losses,
+            };
+            let players_string = ranked_vec
                 .iter()
                 .enumerate()
-                .map(|(index, player)| PlayerString {
-                    rank: index + 1,
-                    name: player.name.clone(),
-                    score: player.rating.rating(),
-                    wins: player.wins,
-                    losses: player.losses,
-                })
+                .map(|(index, (id, player))| player_string(index + 1, *id, player))
+                .collect();
+            let provisional_string = provisional_vec
+                .iter()
+                .map(|(id, player)| player_string(0, *id, player))
+                .collect();
+            let inactive_string = inactive_vec
+                .iter()
+                .map(|(id, player)| player_string(0, *id, player))
                 .collect();
 
             let context = Context {
                 id: id.clone(),
+                rating_system: rating_system.name(),
+                conservative: settings.ranking_mode == RankingMode::Conservative,
+                conservative_k: settings.conservative_k,
+                min_games: settings.min_games,
+                max_uncertainty: settings.max_uncertainty,
                 matches: matches_string,
                 players: players_string,
+                provisional: provisional_string,
+                inactive_days: settings.inactive_days,
+                inactive: inactive_string,
             };
 
             let mut rendered = tt.render("/", &context).map_err(|err| err.to_string())?;
@@ -318,3 +836,62 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
         .run(req, env)
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn games() -> GamesString {
+        let team = |id: u16, name: &str, rating: f64| GameTeamString {
+            players: vec![GamePlayerString {
+                id,
+                name: name.to_string(),
+            }],
+            rating,
+        };
+
+        GamesString {
+            games: vec![GameString {
+                id: 4,
+                teams: vec![team(0, "Ann", 27.5), team(1, "Bob", 22.5)],
+                prediction: Prediction {
+                    team1: 0.75,
+                    team2: 0.25,
+                    quality: None,
+                },
+            }],
+            seed: 7,
+            strategy: Strategy::Balanced,
+        }
+    }
+
+    #[test]
+    fn test_generated_games_json() {
+        assert!(!accepts_html(None));
+        assert!(!accepts_html(Some("application/json")));
+        assert!(!accepts_html(Some("*/*")));
+
+        let json = serde_json::to_value(games()).unwrap();
+        assert_eq!(json["seed"], 7);
+        assert_eq!(json["strategy"], "balanced");
+        assert_eq!(json["games"][0]["id"], 4);
+        assert_eq!(json["games"][0]["teams"][1]["players"][0]["name"], "Bob");
+        assert_eq!(json["games"][0]["prediction"]["team1"], 0.75);
+    }
+
+    #[test]
+    fn test_generated_games_html() {
+        assert!(accepts_html(Some("text/html")));
+        assert!(accepts_html(Some(
+            "text/html,application/xhtml+xml,*/*;q=0.8"
+        )));
+
+        let html = render_games(&games()).unwrap();
+        assert!(html.contains("Game 4<br>"));
+        assert!(html.contains("Team 1: Ann, (Rating 27.50)<br>"));
+        assert!(html.contains("Team 2: Bob, (Rating 22.50)<br>"));
+        assert!(html.contains("Win Chance: 75% - 25%<br>"));
+        assert!(html.contains("Seed: 7<br>"));
+        assert!(html.contains("Strategy: balanced<br>"));
+    }
+}
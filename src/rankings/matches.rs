@@ -1,11 +1,221 @@
+use super::session::Session;
+use super::{Player, PlayerError};
+use crate::RatingType;
+
+use std::collections::{HashMap, HashSet};
+
 use serde::{Deserialize, Serialize};
+use skillratings::Outcomes;
 use worker::*;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Team {
+    pub(crate) players: Vec<u16>,
+    pub(crate) place: usize, // 1 is first place, teams with the same place drew
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) score: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Match {
     pub(crate) id: u16,
-    pub(crate) team1: Vec<u16>, // Always the winning team
-    pub(crate) team2: Vec<u16>,
+    pub(crate) teams: Vec<Team>, // Ordered by place
+    #[serde(default)]
+    pub(crate) played_at: u64, // Milliseconds since epoch, 0 for matches recorded before timestamps
+    #[serde(default)]
+    pub(crate) recorded_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) note: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) session: Option<u16>,
+}
+
+/// Rating of a player before and after a match
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RatingChange {
+    pub(crate) player: u16,
+    pub(crate) before: RatingType,
+    pub(crate) after: RatingType,
+}
+
+impl Match {
+    /// Result of the match for the provided team, only a team alone in first place wins
+    pub fn outcome(&self, team: &Team) -> Outcomes {
+        let first = self
+            .teams
+            .iter()
+            .map(|t| t.place)
+            .min()
+            .unwrap_or(team.place);
+        let tied = self.teams.iter().filter(|t| t.place == team.place).count();
+
+        if team.place > first {
+            Outcomes::LOSS
+        } else if tied > 1 {
+            Outcomes::DRAW
+        } else {
+            Outcomes::WIN
+        }
+    }
+
+    pub fn is_draw(&self) -> bool {
+        self.teams
+            .iter()
+            .any(|team| self.outcome(team) == Outcomes::DRAW)
+    }
+
+    pub fn scores(&self) -> Vec<Option<f64>> {
+        self.teams.iter().map(|team| team.score).collect()
+    }
+
+    pub fn players(&self) -> Vec<u16> {
+        self.teams
+            .iter()
+            .flat_map(|team| team.players.clone())
+            .collect()
+    }
+
+    /// Checks that the match can be rated. When `session_only` is set every player has to be in
+    /// the active session.
+    pub fn validate(
+        &self,
+        players: &HashMap<u16, Player<RatingType>>,
+        session: Option<&Session>,
+        session_only: bool,
+    ) -> std::result::Result<(), MatchError> {
+        if self.teams.len() < 2 {
+            return Err(MatchError::NotEnoughTeams);
+        }
+        if let Some(team) = self.teams.iter().position(|team| team.players.is_empty()) {
+            return Err(MatchError::EmptyTeam { team });
+        }
+
+        let mut unknown: Vec<u16> = self
+            .players()
+            .into_iter()
+            .filter(|player| !players.contains_key(player))
+            .collect();
+        unknown.sort();
+        unknown.dedup();
+        if !unknown.is_empty() {
+            return Err(MatchError::UnknownPlayers { players: unknown });
+        }
+
+        let mut duplicates = vec![];
+        let mut multiple_teams = vec![];
+        let mut seen: HashSet<u16> = HashSet::new();
+        self.teams.iter().for_each(|team| {
+            let mut team_seen: HashSet<u16> = HashSet::new();
+            team.players.iter().for_each(|player| {
+                if !team_seen.insert(*player) {
+                    duplicates.push(*player);
+                } else if !seen.insert(*player) {
+                    multiple_teams.push(*player);
+                }
+            });
+        });
+        if !duplicates.is_empty() {
+            duplicates.sort();
+            duplicates.dedup();
+            return Err(MatchError::DuplicatePlayers {
+                players: duplicates,
+            });
+        }
+        if !multiple_teams.is_empty() {
+            multiple_teams.sort();
+            multiple_teams.dedup();
+            return Err(MatchError::PlayersOnMultipleTeams {
+                players: multiple_teams,
+            });
+        }
+
+        if session_only {
+            let session = session.ok_or(MatchError::NoSession)?;
+            let mut missing: Vec<u16> = seen
+                .into_iter()
+                .filter(|player| !session.players.contains_key(player))
+                .collect();
+            if !missing.is_empty() {
+                missing.sort();
+                return Err(MatchError::NotInSession { players: missing });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reasons a submitted match can't be rated
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum MatchError {
+    NotEnoughTeams,
+    EmptyTeam { team: usize },
+    UnknownPlayers { players: Vec<u16> },
+    DuplicatePlayers { players: Vec<u16> },
+    PlayersOnMultipleTeams { players: Vec<u16> },
+    NoSession,
+    NotInSession { players: Vec<u16> },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LegacyOutcome {
+    #[default]
+    Win,
+    Draw,
+}
+
+/// Matches recorded before multi team support always had two teams
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum MatchRecord {
+    Teams(Match),
+    TwoTeams {
+        id: u16,
+        team1: Vec<u16>, // Always the winning team unless the match is a draw
+        team2: Vec<u16>,
+        #[serde(default)]
+        outcome: LegacyOutcome,
+    },
+}
+
+impl From<MatchRecord> for Match {
+    fn from(record: MatchRecord) -> Self {
+        match record {
+            MatchRecord::Teams(m) => m,
+            MatchRecord::TwoTeams {
+                id,
+                team1,
+                team2,
+                outcome,
+            } => {
+                let place2 = match outcome {
+                    LegacyOutcome::Win => 2,
+                    LegacyOutcome::Draw => 1,
+                };
+
+                Match {
+                    id,
+                    played_at: 0,
+                    recorded_at: 0,
+                    note: None,
+                    session: None,
+                    teams: vec![
+                        Team {
+                            players: team1,
+                            place: 1,
+                            score: None,
+                        },
+                        Team {
+                            players: team2,
+                            place: place2,
+                            score: None,
+                        },
+                    ],
+                }
+            }
+        }
+    }
 }
 
 pub async fn setup(state: &State) -> Result<()> {
@@ -16,23 +226,286 @@ pub async fn setup(state: &State) -> Result<()> {
 }
 
 pub async fn get(state: &State) -> Result<Vec<Match>> {
-    state.storage().get("matches").await
+    let matches: Vec<MatchRecord> = state.storage().get("matches").await?;
+    Ok(matches.into_iter().map(Match::from).collect())
 }
 
-pub async fn create(state: &State, m: Match) -> Result<()> {
-    let next_match_id: u16 = state.storage().get("next_match_id").await?;
-    let mut matches: Vec<Match> = state.storage().get("matches").await?;
+/// Builds the stored version of a submitted match. Matches without a played at time are assumed
+/// to have been played when they were recorded.
+pub fn new_match(m: Match, id: u16, session: Option<&Session>) -> Match {
+    let mut teams = m.teams;
+    teams.sort_by_key(|team| team.place);
 
-    let new_match = Match {
-        id: next_match_id,
-        team1: m.team1,
-        team2: m.team2,
+    let recorded_at = Date::now().as_millis();
+    let played_at = match m.played_at {
+        0 => recorded_at,
+        played_at => played_at,
     };
-    matches.push(new_match);
 
-    state.storage().put("matches", matches).await?;
-    state
-        .storage()
-        .put("next_match_id", next_match_id + 1)
-        .await
+    Match {
+        id,
+        teams,
+        played_at,
+        recorded_at,
+        note: m.note.filter(|note| !note.is_empty()),
+        session: session.map(|s| s.id),
+    }
+}
+
+/// Replaces every reference to `from` with `into`. Players who were ever on opposing teams can't
+/// be merged.
+pub fn merge_players(
+    matches: &mut [Match],
+    from: u16,
+    into: u16,
+) -> std::result::Result<(), PlayerError> {
+    let opposing = matches.iter().find(|m| {
+        let from_team = m.teams.iter().position(|team| team.players.contains(&from));
+        let into_team = m.teams.iter().position(|team| team.players.contains(&into));
+        matches!((from_team, into_team), (Some(from_team), Some(into_team)) if from_team != into_team)
+    });
+    if let Some(m) = opposing {
+        return Err(PlayerError::OpposingTeams { match_id: m.id });
+    }
+
+    matches
+        .iter_mut()
+        .flat_map(|m| m.teams.iter_mut())
+        .filter(|team| team.players.contains(&from))
+        .for_each(|team| {
+            team.players.retain(|player| *player != from);
+            if !team.players.contains(&into) {
+                team.players.push(into);
+            }
+        });
+    Ok(())
+}
+
+/// Returns false if there is no match with the provided id
+pub fn delete(matches: &mut Vec<Match>, id: u16) -> bool {
+    let len = matches.len();
+    matches.retain(|m| m.id != id);
+
+    matches.len() != len
+}
+
+/// Replaces the teams, played at time and note of the match with the same id. Returns false if
+/// there is no match with the provided id.
+pub fn update(matches: &mut [Match], m: Match) -> bool {
+    match matches.iter_mut().find(|existing| existing.id == m.id) {
+        Some(existing) => {
+            let mut teams = m.teams;
+            teams.sort_by_key(|team| team.place);
+
+            existing.teams = teams;
+            if m.played_at != 0 {
+                existing.played_at = m.played_at;
+            }
+            existing.note = m.note.filter(|note| !note.is_empty());
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn team(players: Vec<u16>, place: usize) -> Team {
+        Team {
+            players,
+            place,
+            score: None,
+        }
+    }
+
+    #[test]
+    fn test_outcome() {
+        let m = Match {
+            id: 0,
+            played_at: 0,
+            recorded_at: 0,
+            note: None,
+            session: None,
+            teams: vec![
+                team(vec![0], 1),
+                team(vec![1], 1),
+                team(vec![2], 3),
+                team(vec![3], 4),
+            ],
+        };
+        let outcomes: Vec<Outcomes> = m.teams.iter().map(|t| m.outcome(t)).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcomes::DRAW,
+                Outcomes::DRAW,
+                Outcomes::LOSS,
+                Outcomes::LOSS
+            ]
+        );
+        assert!(m.is_draw());
+
+        let m = Match {
+            id: 0,
+            played_at: 0,
+            recorded_at: 0,
+            note: None,
+            session: None,
+            teams: vec![team(vec![0, 1], 1), team(vec![2, 3], 2)],
+        };
+        assert_eq!(m.outcome(&m.teams[0]), Outcomes::WIN);
+        assert_eq!(m.outcome(&m.teams[1]), Outcomes::LOSS);
+        assert!(!m.is_draw());
+    }
+
+    #[test]
+    fn test_validate() {
+        let player = Player {
+            name: "".to_string(),
+            rating: RatingType::from((25.0, 25.0 / 3.0)),
+            wins: 0,
+            losses: 0,
+            draws: 0,
+            initial_score: None,
+            archived: false,
+        };
+        let players: HashMap<u16, Player<RatingType>> =
+            (0..4).map(|id| (id, player.clone())).collect();
+        let m = |teams: Vec<Team>| Match {
+            id: 0,
+            played_at: 0,
+            recorded_at: 0,
+            note: None,
+            session: None,
+            teams,
+        };
+
+        let valid = m(vec![team(vec![0, 1], 1), team(vec![2, 3], 2)]);
+        assert_eq!(valid.validate(&players, None, false), Ok(()));
+        assert_eq!(
+            m(vec![team(vec![0, 1], 1)]).validate(&players, None, false),
+            Err(MatchError::NotEnoughTeams)
+        );
+        assert_eq!(
+            m(vec![team(vec![0], 1), team(vec![], 2)]).validate(&players, None, false),
+            Err(MatchError::EmptyTeam { team: 1 })
+        );
+        assert_eq!(
+            m(vec![team(vec![0, 7], 1), team(vec![5, 7], 2)]).validate(&players, None, false),
+            Err(MatchError::UnknownPlayers {
+                players: vec![5, 7]
+            })
+        );
+        assert_eq!(
+            m(vec![team(vec![0, 0], 1), team(vec![1], 2)]).validate(&players, None, false),
+            Err(MatchError::DuplicatePlayers { players: vec![0] })
+        );
+        assert_eq!(
+            m(vec![team(vec![0, 1], 1), team(vec![1, 2], 2)]).validate(&players, None, false),
+            Err(MatchError::PlayersOnMultipleTeams { players: vec![1] })
+        );
+
+        let session = Session {
+            id: 0,
+            players: HashMap::from([(0, 0), (1, 0), (2, 0)]),
+            most_played: 0,
+            pending: vec![],
+            next_pending_id: 0,
+            teammates: HashMap::new(),
+            opponents: HashMap::new(),
+        };
+        assert_eq!(
+            valid.validate(&players, None, true),
+            Err(MatchError::NoSession)
+        );
+        assert_eq!(
+            valid.validate(&players, Some(&session), true),
+            Err(MatchError::NotInSession { players: vec![3] })
+        );
+        assert_eq!(valid.validate(&players, Some(&session), false), Ok(()));
+    }
+
+    #[test]
+    fn test_merge_players() {
+        let m = |id: u16, teams: Vec<Team>| Match {
+            id,
+            played_at: 0,
+            recorded_at: 0,
+            note: None,
+            session: None,
+            teams,
+        };
+        let mut matches = vec![
+            m(0, vec![team(vec![0, 1], 1), team(vec![2], 2)]),
+            m(1, vec![team(vec![0, 3], 1), team(vec![1], 2)]),
+        ];
+
+        assert_eq!(
+            merge_players(&mut matches, 3, 1),
+            Err(PlayerError::OpposingTeams { match_id: 1 })
+        );
+        assert_eq!(matches[1].teams[0].players, vec![0, 3]);
+
+        // Both on the same team leaves one entry for the merged player
+        assert_eq!(merge_players(&mut matches, 3, 0), Ok(()));
+        assert_eq!(matches[1].teams[0].players, vec![0]);
+
+        assert_eq!(merge_players(&mut matches, 1, 4), Ok(()));
+        assert_eq!(matches[0].teams[0].players, vec![0, 4]);
+        assert_eq!(matches[1].teams[1].players, vec![4]);
+    }
+
+    #[test]
+    fn test_legacy_match() {
+        let m: Match = serde_json::from_str::<MatchRecord>(
+            r#"{"id":3,"team1":[0,1],"team2":[2],"outcome":"Draw"}"#,
+        )
+        .unwrap()
+        .into();
+        assert_eq!(m.id, 3);
+        assert_eq!(m.played_at, 0);
+        assert_eq!(m.session, None);
+        assert_eq!(m.teams, vec![team(vec![0, 1], 1), team(vec![2], 1)]);
+
+        let m: Match = serde_json::from_str::<MatchRecord>(r#"{"id":4,"team1":[0],"team2":[1]}"#)
+            .unwrap()
+            .into();
+        assert_eq!(m.teams, vec![team(vec![0], 1), team(vec![1], 2)]);
+
+        let m: Match = serde_json::from_str::<MatchRecord>(
+            r#"{"id":5,"teams":[{"players":[0],"place":1,"score":21},{"players":[1],"place":2}]}"#,
+        )
+        .unwrap()
+        .into();
+        assert_eq!(m.teams[0].score, Some(21.0));
+        assert_eq!(m.scores(), vec![Some(21.0), None]);
+    }
+
+    #[test]
+    fn test_update_delete() {
+        let m = |id: u16, players: Vec<u16>| -> Match {
+            serde_json::from_str::<MatchRecord>(&format!(
+                r#"{{"id":{id},"team1":{players:?},"team2":[9]}}"#
+            ))
+            .unwrap()
+            .into()
+        };
+        let mut matches = vec![m(0, vec![0]), m(1, vec![1])];
+
+        let edit = Match {
+            teams: vec![team(vec![9], 2), team(vec![2], 1)],
+            note: Some("".to_string()),
+            ..m(1, vec![2])
+        };
+        assert!(update(&mut matches, edit));
+        assert_eq!(matches[1].teams, vec![team(vec![2], 1), team(vec![9], 2)]);
+        assert_eq!(matches[1].note, None);
+        assert!(!update(&mut matches, m(2, vec![0])));
+
+        assert!(delete(&mut matches, 0));
+        assert!(!delete(&mut matches, 0));
+        assert_eq!(matches.len(), 1);
+    }
 }
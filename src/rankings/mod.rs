@@ -2,11 +2,18 @@ mod matches;
 mod pass;
 mod players;
 mod session;
+mod settings;
+mod system;
 
+use crate::games;
+use crate::games::matchmaking::Game;
+use crate::games::rating::RatingSystem;
+use crate::games::RatingDiff;
 use crate::RatingType;
-pub(crate) use matches::Match;
-pub(crate) use players::{Player, PlayerCreate};
-pub(crate) use session::{Session, SessionCreate};
+pub(crate) use matches::{Match, MatchError, MatchRecord, RatingChange};
+pub(crate) use players::{Player, PlayerCreate, PlayerError, PlayerMerge, PlayerUpdate};
+pub(crate) use session::{PendingResult, Session, SessionCreate};
+pub(crate) use settings::Settings;
 
 use futures::try_join;
 use std::collections::HashMap;
@@ -65,6 +72,263 @@ impl Client {
 #[derive(Serialize, Deserialize)]
 pub(crate) struct Empty {}
 
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct BoardCreate {
+    pub(crate) pass: String,
+    #[serde(default)]
+    pub(crate) rating_system: RatingSystem,
+}
+
+impl BoardCreate {
+    /// Older clients send only the passphrase as plain text, those boards use the default rating
+    /// system
+    pub(crate) fn from_body(body: String) -> Self {
+        match serde_json::from_str(&body) {
+            Ok(create) => create,
+            Err(_) => BoardCreate {
+                pass: body,
+                rating_system: RatingSystem::default(),
+            },
+        }
+    }
+}
+
+/// Rebuilds player ratings from the recorded matches, optionally with a different rating system or
+/// settings. Changes are only saved when `commit` is set, otherwise they are only returned.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Recompute {
+    #[serde(default)]
+    pub(crate) rating_system: Option<RatingSystem>,
+    #[serde(default)]
+    pub(crate) settings: Option<Settings>,
+    #[serde(default)]
+    pub(crate) commit: bool,
+}
+
+/// Checks an edited match against the stored players. Edited matches may be from an earlier session
+/// so they aren't checked against the active session.
+async fn validate_match(state: &State, m: &Match) -> Result<std::result::Result<(), MatchError>> {
+    let players = players::get(state).await?;
+    Ok(m.validate(&players, None, false))
+}
+
+fn bad_request<T: Serialize>(body: &T) -> Result<Response> {
+    Ok(Response::from_json(body)?.with_status(400))
+}
+
+/// Checks, rates and records a new match. Players, matches and the session are read once and
+/// written together so concurrent submissions can't overwrite each other's rating updates and the
+/// match is validated against the same state it is recorded in. `pending` is the id of the pending
+/// game the match is the result of.
+async fn add_match(
+    state: &State,
+    m: Match,
+    pending: Option<u16>,
+) -> Result<std::result::Result<(), MatchError>> {
+    let info = try_join!(
+        players::get(state),
+        matches::get(state),
+        session::get(state),
+        system::get(state),
+        settings::get(state)
+    )?;
+    let (mut players, mut matches, mut session, rating_system, settings) = info;
+    if let Err(err) = m.validate(&players, session.as_ref(), settings.session_players_only) {
+        return Ok(Err(err));
+    }
+    let next_match_id: u16 = state.storage().get("next_match_id").await?;
+
+    let new_match = matches::new_match(m, next_match_id, session.as_ref());
+    games::rate_match(&mut players, &new_match, rating_system, &settings);
+    if let Some(ref mut session) = session {
+        session.add_match(&new_match.players());
+        session.add_pairs(&new_match);
+        session.pending.retain(|game| Some(game.id) != pending);
+    }
+    matches.push(new_match);
+
+    #[derive(Serialize)]
+    struct MatchWrite {
+        players: HashMap<u16, Player<RatingType>>,
+        matches: Vec<Match>,
+        session: Option<Session>,
+        next_match_id: u16,
+    }
+
+    state
+        .storage()
+        .put_multiple(MatchWrite {
+            players,
+            matches,
+            session,
+            next_match_id: next_match_id + 1,
+        })
+        .await?;
+    Ok(Ok(()))
+}
+
+/// Moves every match of one player over to another, removes the old player and rebuilds ratings
+async fn merge_players(
+    state: &State,
+    merge: PlayerMerge,
+) -> Result<std::result::Result<(), PlayerError>> {
+    if merge.from == merge.into {
+        return Ok(Err(PlayerError::SamePlayer));
+    }
+
+    let info = try_join!(
+        players::get(state),
+        matches::get(state),
+        session::get(state),
+        system::get(state),
+        settings::get(state)
+    )?;
+    let (mut players, mut matches, mut session, rating_system, settings) = info;
+
+    if let Some(player) = [merge.from, merge.into]
+        .iter()
+        .find(|player| !players.contains_key(player))
+    {
+        return Ok(Err(PlayerError::UnknownPlayer { player: *player }));
+    }
+    if let Err(err) = matches::merge_players(&mut matches, merge.from, merge.into) {
+        return Ok(Err(err));
+    }
+
+    players.remove(&merge.from);
+    if let Some(ref mut session) = session {
+        session.merge_players(merge.from, merge.into);
+    }
+
+    rebuild(
+        &mut players,
+        &matches,
+        &mut session,
+        rating_system,
+        &settings,
+    );
+
+    state
+        .storage()
+        .put_multiple(ReplayWrite {
+            players,
+            matches,
+            session,
+        })
+        .await?;
+    Ok(Ok(()))
+}
+
+#[derive(Serialize)]
+struct ReplayWrite {
+    players: HashMap<u16, Player<RatingType>>,
+    matches: Vec<Match>,
+    session: Option<Session>,
+}
+
+/// Rates every match again from the players' initial ratings and recounts the session
+fn rebuild(
+    players: &mut HashMap<u16, Player<RatingType>>,
+    matches: &[Match],
+    session: &mut Option<Session>,
+    rating_system: RatingSystem,
+    settings: &Settings,
+) {
+    games::backfill_initial_scores(players, settings);
+    games::replay(players, matches, rating_system, settings);
+    if let Some(ref mut session) = session {
+        session.recount(matches);
+    }
+}
+
+/// Changes the recorded matches with `edit` and rebuilds player ratings and session play counts
+/// from them. Players, matches and the session are written together so the ratings always match
+/// the match list. Returns false without writing anything if `edit` does.
+async fn replay(state: &State, edit: impl FnOnce(&mut Vec<Match>) -> bool) -> Result<bool> {
+    let info = try_join!(
+        players::get(state),
+        matches::get(state),
+        session::get(state),
+        system::get(state),
+        settings::get(state)
+    )?;
+    let (mut players, mut matches, mut session, rating_system, settings) = info;
+    if !edit(&mut matches) {
+        return Ok(false);
+    }
+
+    rebuild(
+        &mut players,
+        &matches,
+        &mut session,
+        rating_system,
+        &settings,
+    );
+
+    state
+        .storage()
+        .put_multiple(ReplayWrite {
+            players,
+            matches,
+            session,
+        })
+        .await?;
+    Ok(true)
+}
+
+async fn recompute(state: &State, body: Recompute) -> Result<Vec<RatingDiff>> {
+    let info = try_join!(
+        players::get(state),
+        matches::get(state),
+        session::get(state),
+        system::get(state),
+        settings::get(state)
+    )?;
+    let (mut players, matches, mut session, current_system, current_settings) = info;
+
+    // Settings of another rating system won't be on the same scale so use its defaults for those
+    let rating_system = body.rating_system.unwrap_or(current_system);
+    let settings = match body.settings {
+        Some(settings) => settings,
+        None if rating_system == current_system => current_settings,
+        None => current_settings.with_rating_system(rating_system),
+    };
+
+    // Stored ratings are on the scale of the current settings
+    games::backfill_initial_scores(&mut players, &current_settings);
+    let before = players.clone();
+    games::replay(&mut players, &matches, rating_system, &settings);
+    let diff = games::rating_diff(&before, &players);
+
+    if body.commit {
+        if let Some(ref mut session) = session {
+            session.recount(&matches);
+        }
+
+        #[derive(Serialize)]
+        struct RecomputeWrite {
+            players: HashMap<u16, Player<RatingType>>,
+            matches: Vec<Match>,
+            session: Option<Session>,
+            rating_system: RatingSystem,
+            settings: Settings,
+        }
+
+        state
+            .storage()
+            .put_multiple(RecomputeWrite {
+                players,
+                matches,
+                session,
+                rating_system,
+                settings,
+            })
+            .await?;
+    }
+
+    Ok(diff)
+}
+
 /// Durable Object storage for match and player data
 #[durable_object]
 pub struct Rankings {
@@ -82,7 +346,7 @@ impl DurableObject for Rankings {
         console_log!("{:?}", req);
         let salt = self.env.secret("PASS_SALT")?.to_string();
 
-        match req.path().split('/').last().unwrap() {
+        match req.path().split('/').next_back().unwrap() {
             "pass" => match req.method() {
                 Method::Get => {
                     let result = pass::get(&self.state).await?;
@@ -100,17 +364,71 @@ impl DurableObject for Rankings {
                 _ => Response::error("Not found", 404),
             },
             "setup" => {
-                let pass: String = req.clone()?.json().await?;
+                let body: BoardCreate = req.clone()?.json().await?;
 
                 let players_fut = players::setup(&self.state);
                 let matches_fut = matches::setup(&self.state);
                 let session_fut = session::reset(&self.state);
+                let system_fut = system::setup(&self.state, body.rating_system);
+                let settings_fut = settings::setup(&self.state, body.rating_system);
 
-                try_join!(players_fut, matches_fut, session_fut)?;
-                pass::set(&self.state, pass, salt).await?;
+                try_join!(
+                    players_fut,
+                    matches_fut,
+                    session_fut,
+                    system_fut,
+                    settings_fut
+                )?;
+                pass::set(&self.state, body.pass, salt).await?;
 
                 Response::from_json(&Empty {})
             }
+            "rating-system" => match req.method() {
+                Method::Get => {
+                    let rating_system = system::get(&self.state).await?;
+                    Response::from_json(&rating_system)
+                }
+                _ => Response::error("Not Found", 404),
+            },
+            "settings" => match req.method() {
+                Method::Get => {
+                    let settings = settings::get(&self.state).await?;
+                    Response::from_json(&settings)
+                }
+                Method::Put => {
+                    let body: Settings = req.clone()?.json().await?;
+                    if !body.is_valid() {
+                        return Response::error("Invalid settings", 400);
+                    }
+
+                    settings::set(&self.state, body).await?;
+                    Response::from_json(&Empty {})
+                }
+                _ => Response::error("Not Found", 404),
+            },
+            "recompute" => match req.method() {
+                Method::Post => {
+                    let body: Recompute = req.clone()?.json().await?;
+                    if body.settings.is_some_and(|settings| !settings.is_valid()) {
+                        return Response::error("Invalid settings", 400);
+                    }
+
+                    let diff = recompute(&self.state, body).await?;
+                    Response::from_json(&diff)
+                }
+                _ => Response::error("Not Found", 404),
+            },
+            "add-match" => match req.method() {
+                Method::Post | Method::Put => {
+                    let body: Match = req.clone()?.json::<MatchRecord>().await?.into();
+
+                    if let Err(err) = add_match(&self.state, body, None).await? {
+                        return bad_request(&err);
+                    }
+                    Response::from_json(&Empty {})
+                }
+                _ => Response::error("Not Found", 404),
+            },
             "players" => match req.method() {
                 Method::Get => {
                     let players = players::get(&self.state).await?;
@@ -118,13 +436,51 @@ impl DurableObject for Rankings {
                 }
                 Method::Post => {
                     let body: PlayerCreate = req.clone()?.json().await?;
+                    let settings = settings::get(&self.state).await?;
 
-                    players::create(&self.state, body).await?;
+                    players::create(&self.state, body, settings).await?;
                     Response::from_json(&Empty {})
                 }
-                Method::Put => {
-                    let body: HashMap<u16, Player<RatingType>> = req.clone()?.json().await?;
-                    players::set(&self.state, body).await?;
+                Method::Patch => {
+                    let body: PlayerUpdate = req.clone()?.json().await?;
+                    if body
+                        .name
+                        .as_ref()
+                        .is_some_and(|name| name.trim().is_empty())
+                    {
+                        return bad_request(&PlayerError::EmptyName);
+                    }
+
+                    if !players::update(&self.state, body).await? {
+                        return Response::error("Player not found", 404);
+                    }
+                    Response::from_json(&Empty {})
+                }
+                Method::Delete => {
+                    let body: u16 = req.clone()?.json().await?;
+                    let matches = matches::get(&self.state)
+                        .await?
+                        .iter()
+                        .filter(|m| m.players().contains(&body))
+                        .count();
+                    if matches > 0 {
+                        return bad_request(&PlayerError::HasMatches { matches });
+                    }
+
+                    if !players::delete(&self.state, body).await? {
+                        return Response::error("Player not found", 404);
+                    }
+                    Response::from_json(&Empty {})
+                }
+                _ => Response::error("Not Found", 404),
+            },
+            "merge-players" => match req.method() {
+                Method::Post => {
+                    let body: PlayerMerge = req.clone()?.json().await?;
+
+                    if let Err(err) = merge_players(&self.state, body).await? {
+                        return bad_request(&err);
+                    }
                     Response::from_json(&Empty {})
                 }
                 _ => Response::error("Not Found", 404),
@@ -137,7 +493,62 @@ impl DurableObject for Rankings {
                 Method::Post => {
                     let body: Match = req.clone()?.json().await?;
 
-                    matches::create(&self.state, body).await?;
+                    if let Err(err) = add_match(&self.state, body, None).await? {
+                        return bad_request(&err);
+                    }
+                    Response::from_json(&Empty {})
+                }
+                Method::Put => {
+                    let body: Match = req.clone()?.json().await?;
+                    if let Err(err) = validate_match(&self.state, &body).await? {
+                        return bad_request(&err);
+                    }
+
+                    if !replay(&self.state, |matches| matches::update(matches, body)).await? {
+                        return Response::error("Match not found", 404);
+                    }
+                    Response::from_json(&Empty {})
+                }
+                Method::Delete => {
+                    let body: u16 = req.clone()?.json().await?;
+
+                    if !replay(&self.state, |matches| matches::delete(matches, body)).await? {
+                        return Response::error("Match not found", 404);
+                    }
+                    Response::from_json(&Empty {})
+                }
+                _ => Response::error("Not Found", 404),
+            },
+            "pending" => match req.method() {
+                Method::Post => {
+                    let body: Vec<Game> = req.clone()?.json().await?;
+
+                    let games = session::add_pending(&self.state, body).await?;
+                    Response::from_json(&games)
+                }
+                Method::Put => {
+                    let body: PendingResult = req.clone()?.json().await?;
+                    let session = session::get(&self.state).await?;
+                    let game = session.and_then(|session| {
+                        session.pending.into_iter().find(|game| game.id == body.id)
+                    });
+                    let game = match game {
+                        Some(game) => game,
+                        None => return Response::error("Pending game not found", 404),
+                    };
+
+                    let m = body.to_match(&game);
+                    if let Err(err) = add_match(&self.state, m, Some(game.id)).await? {
+                        return bad_request(&err);
+                    }
+                    Response::from_json(&Empty {})
+                }
+                Method::Delete => {
+                    let body: u16 = req.clone()?.json().await?;
+
+                    if !session::cancel_pending(&self.state, body).await? {
+                        return Response::error("Pending game not found", 404);
+                    }
                     Response::from_json(&Empty {})
                 }
                 _ => Response::error("Not Found", 404),
@@ -175,3 +586,26 @@ impl DurableObject for Rankings {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_board_create_from_body() {
+        assert_eq!(
+            BoardCreate::from_body(r#"{"pass":"secret","rating_system":"Elo"}"#.to_string()),
+            BoardCreate {
+                pass: "secret".to_string(),
+                rating_system: RatingSystem::Elo,
+            }
+        );
+        assert_eq!(
+            BoardCreate::from_body("secret".to_string()),
+            BoardCreate {
+                pass: "secret".to_string(),
+                rating_system: RatingSystem::default(),
+            }
+        );
+    }
+}
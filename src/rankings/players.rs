@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use super::{session, Session, Settings};
 use crate::RatingType;
 
 use serde::{Deserialize, Serialize};
@@ -17,6 +18,40 @@ pub struct Player<RatingType: skillratings::Rating> {
     pub(crate) rating: RatingType,
     pub(crate) wins: u16,
     pub(crate) losses: u16,
+    #[serde(default)]
+    pub(crate) draws: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) initial_score: Option<i32>,
+    #[serde(default)]
+    pub(crate) archived: bool, // Hidden from rankings and sessions but kept in the match history
+}
+
+/// Fields left empty are unchanged
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlayerUpdate {
+    pub(crate) id: u16,
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+    #[serde(default)]
+    pub(crate) archived: Option<bool>,
+}
+
+/// Combines two entries for the same person, `from` is removed and its matches moved to `into`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlayerMerge {
+    pub(crate) from: u16,
+    pub(crate) into: u16,
+}
+
+/// Reasons a change to players can't be made
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum PlayerError {
+    EmptyName,
+    UnknownPlayer { player: u16 },
+    SamePlayer,
+    HasMatches { matches: usize },
+    OpposingTeams { match_id: u16 },
 }
 
 pub async fn setup(state: &State) -> Result<()> {
@@ -30,27 +65,20 @@ pub async fn get(state: &State) -> Result<HashMap<u16, Player<RatingType>>> {
     state.storage().get("players").await
 }
 
-pub async fn set(state: &State, players: HashMap<u16, Player<RatingType>>) -> Result<()> {
-    state.storage().put("players", players).await
-}
-
-pub async fn create(state: &State, create: PlayerCreate) -> Result<()> {
+pub async fn create(state: &State, create: PlayerCreate, settings: Settings) -> Result<()> {
     let next_player_id: u16 = state.storage().get("next_player_id").await?;
     let mut players: HashMap<u16, Player<RatingType>> = state.storage().get("players").await?;
 
-    let rating = match create.score {
-        Some(rating) => RatingType {
-            rating: rating as f64,
-            uncertainty: 25.0 / 3.0,
-        },
-        None => RatingType::new(),
-    };
+    let rating = settings.initial_rating(create.score.map(|score| score as f64));
 
     let new_player = Player {
         name: create.name,
         rating,
         wins: 0,
         losses: 0,
+        draws: 0,
+        initial_score: create.score,
+        archived: false,
     };
     players.insert(next_player_id, new_player);
 
@@ -60,3 +88,57 @@ pub async fn create(state: &State, create: PlayerCreate) -> Result<()> {
         .put("next_player_id", next_player_id + 1)
         .await
 }
+
+#[derive(Serialize)]
+struct PlayerWrite {
+    players: HashMap<u16, Player<RatingType>>,
+    session: Option<Session>,
+}
+
+/// Archived players are taken out of the active session and their pending games are cancelled.
+/// Returns false if there is no player with the provided id.
+pub async fn update(state: &State, update: PlayerUpdate) -> Result<bool> {
+    let mut players = get(state).await?;
+    let mut session = session::get(state).await?;
+
+    match players.get_mut(&update.id) {
+        Some(player) => {
+            if let Some(name) = update.name {
+                player.name = name;
+            }
+            if let Some(archived) = update.archived {
+                player.archived = archived;
+            }
+        }
+        None => return Ok(false),
+    }
+    if let (Some(true), Some(ref mut session)) = (update.archived, &mut session) {
+        session.remove_player(update.id);
+    }
+
+    state
+        .storage()
+        .put_multiple(PlayerWrite { players, session })
+        .await?;
+    Ok(true)
+}
+
+/// Removes the player from the leaderboard and the active session, cancelling their pending games.
+/// Returns false if there is no player with the provided id.
+pub async fn delete(state: &State, id: u16) -> Result<bool> {
+    let mut players = get(state).await?;
+    let mut session = session::get(state).await?;
+
+    if players.remove(&id).is_none() {
+        return Ok(false);
+    }
+    if let Some(ref mut session) = session {
+        session.remove_player(id);
+    }
+
+    state
+        .storage()
+        .put_multiple(PlayerWrite { players, session })
+        .await?;
+    Ok(true)
+}
@@ -1,12 +1,216 @@
+use super::matches::Team;
+use super::Match;
+use crate::games::matchmaking::Game;
+
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 use worker::*;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Session {
+    #[serde(default)]
+    pub(crate) id: u16, // 0 for sessions started before sessions had ids
     pub(crate) players: HashMap<u16, u16>,
     pub(crate) most_played: u16,
+    #[serde(default)]
+    pub(crate) pending: Vec<Game>, // Generated games waiting for a result
+    #[serde(default)]
+    pub(crate) next_pending_id: u16,
+    #[serde(default)]
+    pub(crate) teammates: HashMap<u16, HashMap<u16, u16>>, // Times each pair played on the same team
+    #[serde(default)]
+    pub(crate) opponents: HashMap<u16, HashMap<u16, u16>>, // Times each pair played against each other
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingOutcome {
+    Team1,
+    Team2,
+    Draw,
+}
+
+/// Result of a pending game
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingResult {
+    pub(crate) id: u16,
+    pub(crate) outcome: PendingOutcome,
+}
+
+impl PendingResult {
+    /// Match to record for the pending game
+    pub fn to_match(&self, game: &Game) -> Match {
+        let (place1, place2) = match self.outcome {
+            PendingOutcome::Team1 => (1, 2),
+            PendingOutcome::Team2 => (2, 1),
+            PendingOutcome::Draw => (1, 1),
+        };
+
+        Match {
+            id: 0,
+            teams: vec![
+                Team {
+                    players: game.team1.clone(),
+                    place: place1,
+                    score: None,
+                },
+                Team {
+                    players: game.team2.clone(),
+                    place: place2,
+                    score: None,
+                },
+            ],
+            played_at: 0,
+            recorded_at: 0,
+            note: None,
+            session: None,
+        }
+    }
+}
+
+impl Session {
+    pub fn new(id: u16, players: &[u16]) -> Self {
+        Session {
+            id,
+            players: players.iter().map(|player| (*player, 0)).collect(),
+            next_pending_id: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Counts the matches each player has played in this session and the pairs they played in from
+    /// the recorded matches
+    pub fn recount(&mut self, matches: &[Match]) {
+        let id = self.id;
+        self.players.values_mut().for_each(|count| *count = 0);
+
+        matches
+            .iter()
+            .filter(|m| m.session == Some(id))
+            .flat_map(|m| m.players())
+            .for_each(|player| *self.players.entry(player).or_insert(0) += 1);
+
+        self.most_played = self.players.values().copied().max().unwrap_or(0);
+        self.count_pairs(matches);
+    }
+
+    /// Counts how often each pair of players were teammates and opponents in this session from the
+    /// recorded matches
+    pub fn count_pairs(&mut self, matches: &[Match]) {
+        let id = self.id;
+        self.teammates.clear();
+        self.opponents.clear();
+
+        matches
+            .iter()
+            .filter(|m| m.session == Some(id))
+            .for_each(|m| self.add_pairs(m));
+    }
+
+    /// Counts one more time together for every pair of players in the match
+    pub fn add_pairs(&mut self, m: &Match) {
+        for (i, team) in m.teams.iter().enumerate() {
+            for (j, player) in team.players.iter().enumerate() {
+                for other in &team.players[j + 1..] {
+                    add_pair(&mut self.teammates, *player, *other);
+                }
+                for other_team in &m.teams[i + 1..] {
+                    for other in &other_team.players {
+                        add_pair(&mut self.opponents, *player, *other);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Times the players were on the same team this session
+    pub fn teammate_count(&self, player: u16, other: u16) -> u16 {
+        pair_count(&self.teammates, player, other)
+    }
+
+    /// Times the players were on opposing teams this session
+    pub fn opponent_count(&self, player: u16, other: u16) -> u16 {
+        pair_count(&self.opponents, player, other)
+    }
+
+    /// Players who are in a pending game
+    pub fn pending_players(&self) -> Vec<u16> {
+        players_of(self.pending.iter())
+    }
+
+    /// Players who were already in a pending game when the pending games with the seed were
+    /// generated. Games are given increasing ids so these are the games before the first game from
+    /// the seed, or every pending game if none are from the seed.
+    pub fn pending_players_before(&self, seed: u64) -> Vec<u16> {
+        let first = self
+            .pending
+            .iter()
+            .filter(|game| game.seed == Some(seed))
+            .map(|game| game.id)
+            .min();
+
+        match first {
+            Some(first) => players_of(self.pending.iter().filter(|game| game.id < first)),
+            None => self.pending_players(),
+        }
+    }
+
+    /// Takes the player out of the session, cancelling their pending games
+    pub fn remove_player(&mut self, player: u16) {
+        self.players.remove(&player);
+        self.pending
+            .retain(|game| !game.team1.contains(&player) && !game.team2.contains(&player));
+    }
+
+    /// Gives `into` the place of `from` in the session and its pending games. Pending games with
+    /// both players are cancelled since one player can't play twice in a game.
+    pub fn merge_players(&mut self, from: u16, into: u16) {
+        if let Some(count) = self.players.remove(&from) {
+            self.players.entry(into).or_insert(count);
+        }
+        self.pending.retain(|game| {
+            let players = players_of(std::iter::once(game));
+            !players.contains(&from) || !players.contains(&into)
+        });
+        self.pending
+            .iter_mut()
+            .flat_map(|game| [&mut game.team1, &mut game.team2])
+            .for_each(|team| {
+                team.iter_mut()
+                    .filter(|player| **player == from)
+                    .for_each(|player| *player = into)
+            });
+    }
+
+    /// Counts one more match for each of the players
+    pub fn add_match(&mut self, players: &[u16]) {
+        players.iter().for_each(|player| {
+            let count = self.players.entry(*player).or_insert(0);
+            *count += 1;
+            self.most_played = self.most_played.max(*count);
+        });
+    }
+}
+
+fn players_of<'a>(games: impl Iterator<Item = &'a Game>) -> Vec<u16> {
+    games
+        .flat_map(|game| game.team1.iter().chain(game.team2.iter()))
+        .copied()
+        .collect()
+}
+
+// Pairs are stored under both players so either can be looked up first
+fn add_pair(pairs: &mut HashMap<u16, HashMap<u16, u16>>, player: u16, other: u16) {
+    *pairs.entry(player).or_default().entry(other).or_insert(0) += 1;
+    *pairs.entry(other).or_default().entry(player).or_insert(0) += 1;
+}
+
+fn pair_count(pairs: &HashMap<u16, HashMap<u16, u16>>, player: u16, other: u16) -> u16 {
+    pairs
+        .get(&player)
+        .and_then(|others| others.get(&other))
+        .copied()
+        .unwrap_or(0)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -20,18 +224,28 @@ pub async fn reset(state: &State) -> Result<()> {
     state.storage().put("session", session).await
 }
 
+/// Sessions started before sessions had ids are read with id 0, so new sessions start counting from
+/// 1 and never share an id with them
+const FIRST_SESSION_ID: u16 = 1;
+
 pub async fn start(state: &State, body: SessionCreate) -> Result<()> {
-    let mut p: HashMap<u16, u16> = HashMap::new();
+    let next_session_id: Result<u16> = state.storage().get("next_session_id").await;
+    let next_session_id = next_session_id.unwrap_or(FIRST_SESSION_ID);
 
-    body.players.iter().for_each(|player| {
-        p.insert(*player, 0);
-    });
+    // Written together so a failed write can't leave the next session reusing this id
+    #[derive(Serialize)]
+    struct SessionWrite {
+        session: Option<Session>,
+        next_session_id: u16,
+    }
 
-    let session: Option<Session> = Some(Session {
-        players: p,
-        most_played: 0,
-    });
-    state.storage().put("session", session).await
+    state
+        .storage()
+        .put_multiple(SessionWrite {
+            session: Some(Session::new(next_session_id, &body.players)),
+            next_session_id: next_session_id + 1,
+        })
+        .await
 }
 
 pub async fn get(state: &State) -> Result<Option<Session>> {
@@ -42,20 +256,7 @@ pub async fn add_match(state: &State, players: Vec<u16>) -> Result<Session> {
     let session: Option<Session> = state.storage().get("session").await?;
 
     if let Some(mut session) = session {
-        let mut most_played = session.most_played;
-        players.iter().for_each(|player| {
-            session
-                .players
-                .entry(*player)
-                .and_modify(|count| {
-                    *count += 1;
-                    if *count > most_played {
-                        most_played = *count
-                    }
-                })
-                .or_insert(1);
-        });
-        session.most_played = most_played;
+        session.add_match(&players);
 
         state.storage().put("session", &session).await?;
         Ok(session)
@@ -77,3 +278,187 @@ pub async fn add_player(state: &State, players: Vec<u16>) -> Result<Session> {
         Err(Error::RouteNoDataError)
     }
 }
+
+/// Stores generated games as pending games, returning them with their new ids
+pub async fn add_pending(state: &State, games: Vec<Game>) -> Result<Vec<Game>> {
+    let session: Option<Session> = state.storage().get("session").await?;
+    if let Some(mut session) = session {
+        let games: Vec<Game> = games
+            .into_iter()
+            .enumerate()
+            .map(|(index, game)| Game {
+                id: session.next_pending_id + index as u16,
+                ..game
+            })
+            .collect();
+        session.next_pending_id += games.len() as u16;
+        session.pending.extend(games.iter().cloned());
+
+        state.storage().put("session", &session).await?;
+        Ok(games)
+    } else {
+        Err(Error::RouteNoDataError)
+    }
+}
+
+/// Returns false if there is no pending game with the provided id
+pub async fn cancel_pending(state: &State, id: u16) -> Result<bool> {
+    let session: Option<Session> = state.storage().get("session").await?;
+    match session {
+        Some(mut session) if session.pending.iter().any(|game| game.id == id) => {
+            session.pending.retain(|game| game.id != id);
+            state.storage().put("session", &session).await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skillratings::Outcomes;
+
+    #[test]
+    fn test_pending_result() {
+        let game = Game {
+            id: 3,
+            team1: vec![0, 1],
+            team2: vec![2, 3],
+            seed: None,
+        };
+        let result = |outcome| PendingResult { id: 3, outcome }.to_match(&game);
+
+        let m = result(PendingOutcome::Team1);
+        assert_eq!(m.outcome(&m.teams[0]), Outcomes::WIN);
+        assert_eq!(m.teams[0].players, vec![0, 1]);
+
+        let m = result(PendingOutcome::Team2);
+        assert_eq!(m.outcome(&m.teams[1]), Outcomes::WIN);
+        assert_eq!(m.teams[1].players, vec![2, 3]);
+
+        assert!(result(PendingOutcome::Draw).is_draw());
+
+        let session = Session {
+            pending: vec![game],
+            next_pending_id: 4,
+            ..Default::default()
+        };
+        assert_eq!(session.pending_players(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_pending_players_before() {
+        let game = |id: u16, players: [u16; 2], seed: u64| Game {
+            id,
+            team1: vec![players[0]],
+            team2: vec![players[1]],
+            seed: Some(seed),
+        };
+        let session = Session {
+            pending: vec![game(1, [0, 1], 7), game(2, [2, 3], 8), game(3, [4, 5], 8)],
+            ..Default::default()
+        };
+
+        assert_eq!(session.pending_players_before(7), Vec::<u16>::new());
+        assert_eq!(session.pending_players_before(8), vec![0, 1]);
+        assert_eq!(session.pending_players_before(9), session.pending_players());
+    }
+
+    #[test]
+    fn test_merge_players() {
+        let game = |id: u16, team1: Vec<u16>, team2: Vec<u16>| Game {
+            id,
+            team1,
+            team2,
+            seed: None,
+        };
+        let mut session = Session {
+            players: HashMap::from([(0, 2), (1, 1), (2, 0), (3, 0)]),
+            pending: vec![
+                game(1, vec![0, 2], vec![1, 3]),
+                game(2, vec![0, 1], vec![2, 3]),
+                game(3, vec![0], vec![2]),
+            ],
+            ..Default::default()
+        };
+
+        session.merge_players(0, 1);
+        assert_eq!(session.players.get(&0), None);
+        assert_eq!(session.players[&1], 1);
+        assert_eq!(session.pending, vec![game(3, vec![1], vec![2])]);
+
+        session.remove_player(2);
+        assert_eq!(session.players.get(&2), None);
+        assert!(session.pending.is_empty());
+    }
+
+    #[test]
+    fn test_count_pairs() {
+        let game = Game {
+            id: 0,
+            team1: vec![0, 1],
+            team2: vec![2, 3],
+            seed: None,
+        };
+        let won = PendingResult {
+            id: 0,
+            outcome: PendingOutcome::Team1,
+        }
+        .to_match(&game);
+        let other_session = won.clone();
+        let won = Match {
+            session: Some(2),
+            ..won
+        };
+
+        let mut session = Session {
+            id: 2,
+            ..Default::default()
+        };
+        session.count_pairs(&[won.clone(), won, other_session]);
+
+        assert_eq!(session.teammate_count(0, 1), 2);
+        assert_eq!(session.teammate_count(1, 0), 2);
+        assert_eq!(session.teammate_count(0, 2), 0);
+        assert_eq!(session.opponent_count(3, 0), 2);
+        assert_eq!(session.opponent_count(2, 3), 0);
+        assert_eq!(session.opponent_count(0, 4), 0);
+    }
+
+    #[test]
+    fn test_legacy_session_id() {
+        let legacy: Session =
+            serde_json::from_str(r#"{"players":{"0":1,"1":1},"most_played":1}"#).unwrap();
+        let new = Session::new(FIRST_SESSION_ID, &[0, 1, 2]);
+        assert_ne!(legacy.id, new.id);
+        assert_eq!(new.players.get(&2), Some(&0));
+
+        let game = Game {
+            id: 0,
+            team1: vec![0],
+            team2: vec![1],
+            seed: None,
+        };
+        let m = PendingResult {
+            id: 0,
+            outcome: PendingOutcome::Team1,
+        }
+        .to_match(&game);
+        let matches = [
+            Match {
+                session: Some(legacy.id),
+                ..m.clone()
+            },
+            Match {
+                session: Some(new.id),
+                ..m
+            },
+        ];
+
+        let mut new = new;
+        new.recount(&matches);
+        assert_eq!(new.players[&0], 1);
+        assert_eq!(new.opponent_count(0, 1), 1);
+    }
+}
@@ -0,0 +1,275 @@
+use super::{system, Player};
+use crate::games::rating::{PlayerRating, RankingMode, RatingSystem};
+
+use serde::{Deserialize, Serialize};
+use skillratings::glicko2::Glicko2Rating;
+use skillratings::weng_lin::WengLinConfig;
+use worker::*;
+
+/// Per leaderboard rating parameters
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    /// Only used by TrueSkill and Weng-Lin
+    pub(crate) beta: f64,
+    /// Only used by TrueSkill
+    pub(crate) dynamics: f64,
+    /// Only used by TrueSkill
+    pub(crate) draw_probability: f64,
+    pub(crate) initial_rating: f64,
+    pub(crate) initial_uncertainty: f64,
+    /// Scales rating changes by the margin of victory, 0 ignores scores
+    #[serde(default)]
+    pub(crate) margin_weight: f64,
+    /// Rejects matches with players who aren't in the active session
+    #[serde(default)]
+    pub(crate) session_players_only: bool,
+    #[serde(default)]
+    pub(crate) ranking_mode: RankingMode,
+    /// Uncertainties subtracted from ratings in conservative ranking
+    #[serde(default = "default_conservative_k")]
+    pub(crate) conservative_k: f64,
+    /// Players with fewer matches are provisional
+    #[serde(default)]
+    pub(crate) min_games: u16,
+    /// Players with a higher uncertainty are provisional
+    #[serde(default)]
+    pub(crate) max_uncertainty: Option<f64>,
+    /// Uncertainty gained per day without a match
+    #[serde(default)]
+    pub(crate) uncertainty_decay: f64,
+    /// Days without a match before a player is listed as inactive
+    #[serde(default)]
+    pub(crate) inactive_days: Option<u16>,
+}
+
+const DAY_MILLIS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+
+fn days_since(time: u64, now: u64) -> f64 {
+    now.saturating_sub(time) as f64 / DAY_MILLIS
+}
+
+fn default_conservative_k() -> f64 {
+    3.0
+}
+
+impl Settings {
+    pub fn new(rating_system: RatingSystem) -> Self {
+        let trueskill = Settings {
+            beta: 6.0,
+            dynamics: 0.13,
+            draw_probability: 0.0,
+            initial_rating: 25.0,
+            initial_uncertainty: 25.0 / 3.0,
+            margin_weight: 0.0,
+            session_players_only: false,
+            ranking_mode: RankingMode::Rating,
+            conservative_k: default_conservative_k(),
+            min_games: 0,
+            max_uncertainty: None,
+            uncertainty_decay: 0.0,
+            inactive_days: None,
+        };
+
+        match rating_system {
+            RatingSystem::TrueSkill => trueskill,
+            RatingSystem::WengLin => Settings {
+                beta: WengLinConfig::new().beta,
+                ..trueskill
+            },
+            RatingSystem::Glicko2 => Settings {
+                initial_rating: Glicko2Rating::new().rating,
+                initial_uncertainty: Glicko2Rating::new().deviation,
+                ..trueskill
+            },
+            RatingSystem::Elo => Settings {
+                initial_rating: 1000.0,
+                initial_uncertainty: 0.0,
+                ..trueskill
+            },
+        }
+    }
+
+    /// Settings for another rating system. Settings on the scale of the rating system take the
+    /// defaults of the new rating system, everything else is kept.
+    pub fn with_rating_system(&self, rating_system: RatingSystem) -> Self {
+        let defaults = Settings::new(rating_system);
+
+        Settings {
+            beta: defaults.beta,
+            dynamics: defaults.dynamics,
+            draw_probability: defaults.draw_probability,
+            initial_rating: defaults.initial_rating,
+            initial_uncertainty: defaults.initial_uncertainty,
+            max_uncertainty: defaults.max_uncertainty,
+            uncertainty_decay: defaults.uncertainty_decay,
+            ..*self
+        }
+    }
+
+    /// Rating given to new players, `score` overrides the initial rating
+    pub fn initial_rating(&self, score: Option<f64>) -> PlayerRating {
+        PlayerRating::from((
+            score.unwrap_or(self.initial_rating),
+            self.initial_uncertainty,
+        ))
+    }
+
+    /// Value players are ordered by on the leaderboard, highest first
+    pub fn ranking_score(&self, rating: &PlayerRating) -> f64 {
+        match self.ranking_mode {
+            RankingMode::Rating => rating.rating,
+            RankingMode::Conservative => rating.conservative(self.conservative_k),
+        }
+    }
+
+    pub fn is_provisional(&self, player: &Player<PlayerRating>) -> bool {
+        let games = player.wins + player.draws + player.losses;
+
+        games < self.min_games
+            || self
+                .max_uncertainty
+                .is_some_and(|max| player.rating.uncertainty > max)
+    }
+
+    /// Rating with the uncertainty gained since the player last played, times are in milliseconds
+    /// since epoch. Players who haven't played keep their rating.
+    pub fn decayed_rating(
+        &self,
+        rating: &PlayerRating,
+        last_played: Option<u64>,
+        now: u64,
+    ) -> PlayerRating {
+        match last_played {
+            Some(last_played) => rating.decayed(
+                days_since(last_played, now),
+                self.uncertainty_decay,
+                self.initial_uncertainty,
+            ),
+            None => *rating,
+        }
+    }
+
+    pub fn is_inactive(&self, last_played: Option<u64>, now: u64) -> bool {
+        match (self.inactive_days, last_played) {
+            (Some(days), Some(last_played)) => days_since(last_played, now) > days as f64,
+            _ => false,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.beta > 0.0
+            && self.dynamics >= 0.0
+            && (0.0..1.0).contains(&self.draw_probability)
+            && self.initial_rating.is_finite()
+            && self.initial_uncertainty >= 0.0
+            && self.margin_weight >= 0.0
+            && self.conservative_k >= 0.0
+            && self.conservative_k.is_finite()
+            && self.max_uncertainty.unwrap_or(0.0) >= 0.0
+            && self.uncertainty_decay >= 0.0
+    }
+}
+
+pub async fn setup(state: &State, rating_system: RatingSystem) -> Result<()> {
+    state
+        .storage()
+        .put("settings", Settings::new(rating_system))
+        .await
+}
+
+/// Leaderboards created before settings existed use the defaults of their rating system
+pub async fn get(state: &State) -> Result<Settings> {
+    let settings: Result<Settings> = state.storage().get("settings").await;
+    match settings {
+        Ok(settings) => Ok(settings),
+        Err(_) => Ok(Settings::new(system::get(state).await?)),
+    }
+}
+
+pub async fn set(state: &State, settings: Settings) -> Result<()> {
+    state.storage().put("settings", settings).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_provisional() {
+        let mut settings = Settings::new(RatingSystem::TrueSkill);
+        let mut player = Player {
+            name: "".to_string(),
+            rating: settings.initial_rating(None),
+            wins: 1,
+            losses: 1,
+            draws: 0,
+            initial_score: None,
+            archived: false,
+        };
+        assert!(!settings.is_provisional(&player));
+
+        settings.min_games = 3;
+        assert!(settings.is_provisional(&player));
+        player.draws = 1;
+        assert!(!settings.is_provisional(&player));
+
+        settings.max_uncertainty = Some(5.0);
+        assert!(settings.is_provisional(&player));
+        player.rating.uncertainty = 4.0;
+        assert!(!settings.is_provisional(&player));
+    }
+
+    #[test]
+    fn test_with_rating_system() {
+        let settings = Settings {
+            min_games: 5,
+            ranking_mode: RankingMode::Conservative,
+            session_players_only: true,
+            inactive_days: Some(30),
+            margin_weight: 0.5,
+            max_uncertainty: Some(4.0),
+            ..Settings::new(RatingSystem::TrueSkill)
+        };
+
+        let elo = settings.with_rating_system(RatingSystem::Elo);
+        assert_eq!(elo.initial_rating, 1000.0);
+        assert_eq!(elo.initial_uncertainty, 0.0);
+        assert_eq!(elo.max_uncertainty, None);
+        assert_eq!(elo.min_games, 5);
+        assert_eq!(elo.ranking_mode, RankingMode::Conservative);
+        assert!(elo.session_players_only);
+        assert_eq!(elo.inactive_days, Some(30));
+        assert_eq!(elo.margin_weight, 0.5);
+
+        assert_eq!(
+            elo.with_rating_system(RatingSystem::TrueSkill),
+            Settings {
+                max_uncertainty: None,
+                ..settings
+            }
+        );
+    }
+
+    #[test]
+    fn test_inactivity() {
+        let mut settings = Settings::new(RatingSystem::TrueSkill);
+        let rating = PlayerRating::from((30.0, 3.0));
+        let day = DAY_MILLIS as u64;
+
+        assert_eq!(settings.decayed_rating(&rating, Some(0), 64 * day), rating);
+        assert!(!settings.is_inactive(Some(0), 1000 * day));
+
+        settings.uncertainty_decay = 0.5;
+        settings.inactive_days = Some(30);
+        assert_eq!(
+            settings
+                .decayed_rating(&rating, Some(day), 65 * day)
+                .uncertainty,
+            5.0
+        );
+        assert_eq!(settings.decayed_rating(&rating, None, 65 * day), rating);
+        assert!(!settings.is_inactive(Some(day), 31 * day));
+        assert!(settings.is_inactive(Some(day), 32 * day));
+        assert!(!settings.is_inactive(None, 32 * day));
+    }
+}
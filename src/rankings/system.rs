@@ -0,0 +1,13 @@
+use crate::games::rating::RatingSystem;
+
+use worker::*;
+
+pub async fn setup(state: &State, rating_system: RatingSystem) -> Result<()> {
+    state.storage().put("rating_system", rating_system).await
+}
+
+/// Leaderboards created before rating systems could be chosen always used TrueSkill
+pub async fn get(state: &State) -> Result<RatingSystem> {
+    let rating_system: Result<RatingSystem> = state.storage().get("rating_system").await;
+    Ok(rating_system.unwrap_or_default())
+}
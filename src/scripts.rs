@@ -18,6 +18,144 @@ function getCookie(name) {
         if (c.indexOf(nameEQ) == 0) return c.substring(nameEQ.length,c.length);
     }
     return null;
+}
+function readSettings() {
+  return {
+    "beta": Number($("#settings-beta").val()),
+    "dynamics": Number($("#settings-dynamics").val()),
+    "draw_probability": Number($("#settings-draw-probability").val()),
+    "initial_rating": Number($("#settings-initial-rating").val()),
+    "initial_uncertainty": Number($("#settings-initial-uncertainty").val()),
+    "margin_weight": Number($("#settings-margin-weight").val()),
+    "session_players_only": $("#settings-session-players-only").is(':checked'),
+    "ranking_mode": $("#settings-ranking-mode").val(),
+    "conservative_k": Number($("#settings-conservative-k").val()),
+    "min_games": Number($("#settings-min-games").val()),
+    "max_uncertainty": $("#settings-max-uncertainty").val() ? Number($("#settings-max-uncertainty").val()) : null,
+    "uncertainty_decay": Number($("#settings-uncertainty-decay").val()),
+    "inactive_days": $("#settings-inactive-days").val() ? Number($("#settings-inactive-days").val()) : null,
+  };
+}
+function matchError(body) {
+  const names = (body.players || []).map(id => $('.team-select').first().find('option[value="' + id + '"]').text() || id);
+  switch (body.error) {
+    case 'not_enough_teams': return 'A match needs at least two teams';
+    case 'empty_team': return 'Team ' + (body.team + 1) + ' has no players';
+    case 'unknown_players': return 'Unknown players: ' + names.join(', ');
+    case 'duplicate_players': return 'Players added to a team twice: ' + names.join(', ');
+    case 'players_on_multiple_teams': return 'Players on more than one team: ' + names.join(', ');
+    case 'no_session': return 'There is no active session';
+    case 'not_in_session': return 'Players not in the session: ' + names.join(', ');
+    default: return 'Could not add match';
+  }
+}
+function playerGroups(text) {
+  return text.split(';').map(group => group.split(',').map(name => name.trim()).filter(name => name).map(name => {
+    const option = $('#matchmake-select option').filter(function () { return $(this).text() == name; });
+    return option.length ? Number(option.val()) : null;
+  }).filter(id => id !== null)).filter(group => group.length > 1);
+}
+function matchmakingError(body) {
+  const names = (body.players || []).map(id => $('#matchmake-select').find('option[value="' + id + '"]').text() || id);
+  switch (body.error) {
+    case 'no_games': return 'Number of Games has to be at least 1';
+    case 'empty_team': return 'Teams need at least one player';
+    case 'not_enough_players': return body.needed + ' players are needed for a game but only ' + body.available + ' can play';
+    case 'unknown_players': return 'Unknown players: ' + names.join(', ');
+    case 'duplicate_players': return 'Players selected twice: ' + names.join(', ');
+    case 'invalid_stability': return 'Matchmaking Stability has to be a number of at least 0';
+    case 'invalid_repeat_penalty': return 'Repeat Penalty has to be a number from 0 to 0.5';
+    case 'group_too_large': return 'More players kept together than fit on a team: ' + names.join(', ');
+    case 'conflicting_constraints': return 'Players both kept together and apart: ' + names.join(', ');
+    case 'unsatisfiable': return 'No matches keep every player together and apart as asked';
+    case 'no_session': return 'Start a session to generate matches';
+    default: return 'Could not generate matches';
+  }
+}
+function generateMatches(preview) {
+  const boardId = document.getElementById("board-id").innerHTML;
+  const passphrase = $('#passphrase').val();
+
+  $.ajax({
+      url: '/' + boardId + '/generate-matches',
+      type: 'PUT',
+      beforeSend: function(request) {
+        request.setRequestHeader("passphrase", passphrase);
+      },
+      data: JSON.stringify({
+        "participants": $('#matchmake-select').val().map(x => Number(x)),
+        "game_info": {
+          "games": Number($("#num-games-select").val()),
+          "players_per_team": Number($("#players-per-select").val()),
+          "team2_players": $("#team2-players-select").val() ? Number($("#team2-players-select").val()) : null,
+          "stability": Number($("#stability-select").val()),
+          "strategy": $("#strategy-select").val(),
+          "repeat_penalty": Number($("#repeat-penalty-select").val()),
+          "seed": $("#seed-select").val() ? Number($("#seed-select").val()) : null,
+          "keep_together": playerGroups($("#keep-together-select").val()),
+          "keep_apart": playerGroups($("#keep-apart-select").val()).flatMap(group => group.flatMap((id, i) => group.slice(i + 1).map(other => [id, other])))
+        },
+        "preview": preview,
+      }),
+  }).done(function (data) {
+    setCookie("passphrase", passphrase)
+    if (!preview) {
+      location.reload();
+      return;
+    }
+
+    const area = $('#preview-area');
+    area.empty();
+    data.games.forEach(function (game) {
+      const names = team => team.players.map(player => player.name).join(', ');
+      const row = $('<tr>');
+      row.append($('<td>').text(names(game.teams[0])));
+      row.append($('<td>').text(names(game.teams[1])));
+      row.append($('<td>').text((game.prediction.team1 * 100).toFixed(0) + '% - ' + (game.prediction.team2 * 100).toFixed(0) + '%'));
+      area.append(row);
+    });
+    $('#preview-seed').text('Seed: ' + data.seed + ', teams picked ' + (data.strategy === 'balanced' ? 'like Balanced' : 'with Greedy'));
+    $('#generate-error').text('');
+  }).fail(function (xhr) {
+    $('#generate-error').text(xhr.responseJSON ? matchmakingError(xhr.responseJSON) : 'Could not generate matches');
+  });
+}
+function recompute(commit) {
+  const boardId = document.getElementById("board-id").innerHTML;
+  const passphrase = $('#passphrase').val();
+  const ratingSystem = $('#recompute-system').val();
+  const body = {
+    "rating_system": ratingSystem,
+    "commit": commit,
+  };
+  if (ratingSystem == $('#recompute-system').data('current')) {
+    body["settings"] = readSettings();
+  }
+
+  $.ajax({
+      url: '/' + boardId + '/recompute',
+      type: 'POST',
+      beforeSend: function(request) {
+        request.setRequestHeader("passphrase", passphrase);
+      },
+      data: JSON.stringify(body),
+      success: function (data) {
+        const area = $('#recompute-area');
+        area.empty();
+        data.forEach(function (diff) {
+          const row = $('<tr>');
+          row.append($('<td>').text(diff.name));
+          row.append($('<td>').text(diff.before.rating.toFixed(2)));
+          row.append($('<td>').text(diff.after.rating.toFixed(2)));
+          row.append($('<td>').text(diff.default_initial ? "Default rating" : "Initial rating"));
+          area.append(row);
+        });
+        setCookie("passphrase", passphrase)
+        if (commit) {
+          $('#recompute-system').data('current', ratingSystem);
+        }
+      },
+  });
 }
       $(document).ready(function () {
         const cookie = getCookie('passphrase');
@@ -25,6 +163,14 @@ function getCookie(name) {
         if (cookie) {
           $('#passphrase').val(cookie);
         }
+        $('#recompute-system').val($('#recompute-system').data('current'));
+        $('#settings-ranking-mode').val($('#settings-ranking-mode').data('current'));
+        $('#preview-recompute').click(function () {
+          recompute(false);
+        });
+        $('#apply-recompute').click(function () {
+          recompute(true);
+        });
         $('#start-session').click(function () {
           const passphrase = $('#passphrase').val();
           const boardId = document.getElementById("board-id").innerHTML;
@@ -45,6 +191,7 @@ function getCookie(name) {
         $('#add-match').click(function () {
           const boardId = document.getElementById("board-id").innerHTML;
           const passphrase = $('#passphrase').val();
+          const playedAt = $('#match-played-at').val();
           $.ajax({
               url: '/' + boardId + '/add-match',
               type: 'PUT',
@@ -53,8 +200,16 @@ function getCookie(name) {
               },
               data: JSON.stringify({
                 "id": 0,
-                "team1": $('#winners-select').val().map(x => Number(x)),
-                "team2": $('#losers-select').val().map(x => Number(x)),
+                "played_at": playedAt ? new Date(playedAt).getTime() : 0,
+                "note": $('#match-note').val() || null,
+                "teams": $('.team-block').map(function () {
+                  const score = $(this).find('.team-score').val();
+                  return {
+                    "players": $(this).find('.team-select').val().map(x => Number(x)),
+                    "place": Number($(this).find('.team-place').val()),
+                    "score": score ? Number(score) : null,
+                  };
+                }).get(),
               }),
             }).done(function () {
                 const successAddMatchToast = document.getElementById('add-match-toast');
@@ -62,29 +217,104 @@ function getCookie(name) {
                 toast.show();
 
                 setCookie("passphrase", passphrase)
+            }).fail(function (xhr) {
+                const error = xhr.responseJSON ? matchError(xhr.responseJSON) : 'Could not add match';
+                $('#add-match-error').text(error);
+                const errorAddMatchToast = document.getElementById('add-match-error-toast');
+                const toast = bootstrap.Toast.getOrCreateInstance(errorAddMatchToast);
+                toast.show();
             });
           });
-        $('#generate-matches').click(function () {
+        $('#predict-match').click(function () {
+          const boardId = document.getElementById("board-id").innerHTML;
+          const teams = $('.team-select').map(function () {
+            return [$(this).val().map(x => Number(x))];
+          }).get();
+          $.ajax({
+              url: '/' + boardId + '/predict',
+              type: 'POST',
+              data: JSON.stringify({
+                "team1": teams[0],
+                "team2": teams[1],
+              }),
+          }).done(function (prediction) {
+            let text = 'Win Chance: ' + (prediction.team1 * 100).toFixed(0) + '% - ' + (prediction.team2 * 100).toFixed(0) + '%';
+            if (prediction.quality != null) {
+              text += ', Match Quality: ' + (prediction.quality * 100).toFixed(0) + '%';
+            }
+            $('#prediction-area').text(text);
+          }).fail(function (xhr) {
+            $('#prediction-area').text(xhr.responseJSON ? matchError(xhr.responseJSON) : 'Could not predict match');
+          });
+        });
+        $('#delete-match').click(function () {
           const boardId = document.getElementById("board-id").innerHTML;
           const passphrase = $('#passphrase').val();
+          $.ajax({
+              url: '/' + boardId + '/matches',
+              type: 'DELETE',
+              beforeSend: function(request) {
+                request.setRequestHeader("passphrase", passphrase);
+              },
+              data: JSON.stringify(Number($('#delete-match-id').val())),
+          }).done(function() {
+            const successDeleteMatchToast = document.getElementById('delete-match-toast');
+            const toast = bootstrap.Toast.getOrCreateInstance(successDeleteMatchToast);
+            toast.show();
 
+            setCookie("passphrase", passphrase)
+          });
+        });
+        $('#add-team').click(function () {
+          const team = $('.team-block').first().clone();
+          team.find('.team-select').val([]);
+          team.find('.team-place').val($('.team-block').length + 1);
+          team.find('.team-score').val('');
+          $('#teams-area').append(team);
+        });
+        $('#generate-matches').click(function () {
+          generateMatches(false);
+        });
+        $('#preview-matches').click(function () {
+          generateMatches(true);
+        });
+        $('.pending-result').click(function () {
+          const boardId = document.getElementById("board-id").innerHTML;
+          const passphrase = $('#passphrase').val();
           $.ajax({
-              url: '/' + boardId + '/generate-matches',
+              url: '/' + boardId + '/pending',
               type: 'PUT',
               beforeSend: function(request) {
                 request.setRequestHeader("passphrase", passphrase);
               },
               data: JSON.stringify({
-                "participants": $('#matchmake-select').val().map(x => Number(x)),
-                "game_info": {
-                  "games": Number($("#num-games-select").val()),
-                  "players_per_team": Number($("#players-per-select").val()),
-                  "stability": Number($("#stability-select").val())
-                }
+                "id": Number($(this).data('game')),
+                "outcome": $(this).data('outcome'),
               }),
-              success: function (data) {
-                document.getElementById("match-area").innerHTML = data;
+          }).done(function () {
+            setCookie("passphrase", passphrase)
+            location.reload();
+          }).fail(function (xhr) {
+            const error = xhr.responseJSON ? matchError(xhr.responseJSON) : 'Could not add match';
+            $('#add-match-error').text(error);
+            const errorAddMatchToast = document.getElementById('add-match-error-toast');
+            const toast = bootstrap.Toast.getOrCreateInstance(errorAddMatchToast);
+            toast.show();
+          });
+        });
+        $('.pending-cancel').click(function () {
+          const boardId = document.getElementById("board-id").innerHTML;
+          const passphrase = $('#passphrase').val();
+          $.ajax({
+              url: '/' + boardId + '/pending',
+              type: 'DELETE',
+              beforeSend: function(request) {
+                request.setRequestHeader("passphrase", passphrase);
               },
+              data: JSON.stringify(Number($(this).data('game'))),
+          }).done(function () {
+            setCookie("passphrase", passphrase)
+            location.reload();
           });
         });
         $('#add-session').click(function () {
@@ -102,6 +332,24 @@ function getCookie(name) {
             location.reload();
           });
         });
+        $('#save-settings').click(function () {
+          const boardId = document.getElementById("board-id").innerHTML;
+          const passphrase = $('#passphrase').val();
+          $.ajax({
+              url: '/' + boardId + '/settings',
+              type: 'PUT',
+              beforeSend: function(request) {
+                request.setRequestHeader("passphrase", passphrase);
+              },
+              data: JSON.stringify(readSettings()),
+          }).done(function() {
+            const successSettingsToast = document.getElementById('save-settings-toast');
+            const toast = bootstrap.Toast.getOrCreateInstance(successSettingsToast);
+            toast.show();
+
+            setCookie("passphrase", passphrase)
+          });
+        });
         $('#stop-session').click(function () {
           const boardId = document.getElementById("board-id").innerHTML;
           const passphrase = $('#passphrase').val();
@@ -144,6 +392,38 @@ function getCookie(name) {
     return null;
 }
 
+function playerError(body) {
+  switch (body.error) {
+    case 'empty_name': return 'Names can not be empty';
+    case 'unknown_player': return 'Unknown player';
+    case 'same_player': return 'Choose two different players';
+    case 'has_matches': return 'This player has played ' + body.matches + ' matches, archive them instead';
+    case 'opposing_teams': return 'These players were on opposing teams in match #' + body.match_id;
+    default: return 'Could not update players';
+  }
+}
+function updatePlayers(type, url, body) {
+  const boardId = document.getElementById("board-id").innerHTML;
+  const passphrase = $('#passphrase').val();
+
+  $.ajax({
+      url: '/' + boardId + url,
+      type: type,
+      beforeSend: function(request) {
+        request.setRequestHeader("passphrase", passphrase);
+      },
+      data: JSON.stringify(body),
+  }).done(function () {
+    setCookie("passphrase", passphrase)
+    location.reload();
+  }).fail(function (xhr) {
+    $('#player-error').text(xhr.responseJSON ? playerError(xhr.responseJSON) : 'Could not update players');
+    const errorToast = document.getElementById('player-error-toast');
+    const toast = bootstrap.Toast.getOrCreateInstance(errorToast);
+    toast.show();
+  });
+}
+
       $(document).ready(function () {
         const cookie = getCookie('passphrase');
         if (cookie) {
@@ -173,6 +453,28 @@ function getCookie(name) {
             setCookie("passphrase", passphrase)
           });
         });
+        $('.rename-player').click(function () {
+          const id = Number($(this).data('player'));
+          updatePlayers('PATCH', '/players', {
+            "id": id,
+            "name": $('.player-name[data-player="' + id + '"]').val(),
+          });
+        });
+        $('.archive-player').click(function () {
+          updatePlayers('PATCH', '/players', {
+            "id": Number($(this).data('player')),
+            "archived": $(this).data('archived'),
+          });
+        });
+        $('.delete-player').click(function () {
+          updatePlayers('DELETE', '/players', Number($(this).data('player')));
+        });
+        $('#merge-players').click(function () {
+          updatePlayers('POST', '/merge-players', {
+            "from": Number($('#merge-from').val()),
+            "into": Number($('#merge-into').val()),
+          });
+        });
       });
     </script>
   </body>
@@ -183,6 +485,13 @@ pub const INDEX: &str = r##"
   <script>
       $(document).ready(function () {
         const boardId = document.getElementById("board-id").innerHTML;
+        $('.timestamp').each(function () {
+          const time = Number($(this).data('time'));
+          $(this).text(time ? new Date(time).toLocaleString() : '-');
+        });
+        $('.player-link').each(function () {
+          $(this).attr('href', '/' + boardId + '/player/' + $(this).data('player'));
+        });
         $('#players').click(function () {
            location.href = '/'+ boardId +'/player'
         });
@@ -194,3 +503,35 @@ pub const INDEX: &str = r##"
   </body>
 </html>
 "##;
+
+pub const PROFILE: &str = r##"
+  <script>
+      $(document).ready(function () {
+        const boardId = document.getElementById("board-id").innerHTML;
+        const playerId = $('#player-id').data('player');
+        $('.timestamp').each(function () {
+          const time = Number($(this).data('time'));
+          $(this).text(time ? new Date(time).toLocaleString() : '-');
+        });
+        $.getJSON('/' + boardId + '/players/' + playerId + '/history', function (history) {
+          if (history.length == 0) {
+            return;
+          }
+          const labels = ['Start'].concat(history.map(entry => '#' + entry.match_id));
+          const ratings = [history[0].before.rating].concat(history.map(entry => entry.after.rating));
+          new Chart(document.getElementById('rating-chart'), {
+            type: 'line',
+            data: {
+              labels: labels,
+              datasets: [{
+                label: 'Rating',
+                data: ratings,
+              }],
+            },
+          });
+        });
+      });
+  </script>
+  </body>
+</html>
+"##;
@@ -25,3 +25,15 @@ pub fn format_float(val: &serde_json::Value, output: &mut String) -> Result<()>
     tinytemplate::format(val, output)?;
     Ok(())
 }
+
+pub fn format_percent(val: &serde_json::Value, output: &mut String) -> Result<()> {
+    if let serde_json::Value::Number(num) = val {
+        if let Some(num) = num.as_f64() {
+            write!(output, "{:.0}%", num * 100.0)?;
+            return Ok(());
+        }
+    }
+
+    tinytemplate::format(val, output)?;
+    Ok(())
+}